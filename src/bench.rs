@@ -0,0 +1,193 @@
+//! Emulation throughput benchmarks, run with `cargo bench -- --nocapture`. Besides the usual
+//! ns/iter, each one prints the emulated clock speed and the host time spent per instruction.
+
+use crate::machine::assembler::assemble;
+use crate::machine::cpu::block::BlockCache;
+use crate::machine::cpu::testing::{self, TestInterface};
+use crate::machine::cpu::{self, emulate, CPUInterface};
+use crate::machine::Machine;
+use crate::midway::MidwayInterface;
+use crate::space_invaders::SpaceInvaders;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use test::Bencher;
+
+/// Cycles emulated per iteration of an instruction mix.
+const CYCLES: u128 = 1_000_000;
+
+const INVADERS_ROM: &str = "roms/invaders.rom";
+
+/// Frames run per iteration of the attract mode benchmark, starting from reset.
+const FRAMES: u32 = 120;
+
+/// Space Invaders runs at 2 MHz and interrupts twice per 60 Hz frame.
+const HALF_FRAME_CYCLES: u128 = 2_000_000 / 120;
+const HALF_FRAME_MICROS: u64 = 1_000_000 / 120;
+
+/// Register arithmetic and logic, no memory operands.
+const ALU: &str = "
+        MVI  B,$FF
+LOOP:   ADD  B
+        ADC  C
+        SUB  D
+        XRA  E
+        ANA  H
+        ORA  L
+        CMP  B
+        INR  C
+        DCR  D
+        RLC
+        DAD  B
+        INX  H
+        DCR  B
+        JNZ  LOOP
+        JMP  LOOP
+";
+
+/// Loads and stores through HL, DE and direct addresses, kept within $2000-$2FFF.
+const MEMORY: &str = "
+        LXI  H,$2000
+        LXI  D,$2800
+LOOP:   MOV  A,M
+        STAX D
+        INX  D
+        MOV  M,A
+        INX  H
+        LDA  $2100
+        STA  $2101
+        MVI  M,$55
+        MOV  A,H
+        ANI  $0F
+        ORI  $20
+        MOV  H,A
+        MOV  A,D
+        ANI  $0F
+        ORI  $20
+        MOV  D,A
+        JMP  LOOP
+";
+
+/// Calls, returns, jumps and stack traffic.
+const BRANCH: &str = "
+        LXI  SP,$2400
+LOOP:   CALL SUB
+        PUSH B
+        PUSH D
+        POP  D
+        POP  B
+        XTHL
+        JZ   SKIP
+SKIP:   JNZ  NEXT
+NEXT:   JMP  LOOP
+SUB:    PUSH PSW
+        POP  PSW
+        RET
+";
+
+/// Runs `b` and prints emulated MHz and ns/instruction from the totals `run` reports as
+/// (instructions, cycles) for each iteration.
+fn measure<F: FnMut() -> (u64, u128)>(name: &str, b: &mut Bencher, mut run: F) {
+    let mut instructions = 0;
+    let mut cycles = 0;
+    let start = Instant::now();
+    b.iter(|| {
+        let (i, c) = run();
+        instructions += i;
+        cycles += c;
+    });
+    let nanos = start.elapsed().as_nanos() as f64;
+    println!(
+        "{}: {:.2} emulated MHz, {:.2} ns/instruction",
+        name,
+        cycles as f64 * 1000.0 / nanos,
+        nanos / instructions as f64
+    );
+}
+
+/// Runs `source` one instruction at a time, or with `cached` through the block cache.
+fn mix(name: &str, source: &str, cached: bool, b: &mut Bencher) {
+    let assembly = assemble(source).unwrap();
+    let mut memory = testing::memory(&assembly.bytes);
+    let mut cpu = cpu::new();
+    let mut interface = TestInterface { input: 0 };
+    let mut blocks = BlockCache::new();
+    measure(name, b, || {
+        let mut state = CPUInterface {
+            cpu: &mut cpu,
+            memory: &mut memory,
+        };
+        let (iters, cycles) = (state.cpu.iters, state.cpu.cycles);
+        if cached {
+            blocks.run(&mut state, &mut interface, CYCLES).unwrap();
+        } else {
+            let mut ran = 0;
+            while ran < CYCLES {
+                ran += u128::from(emulate(&mut state, &mut interface).unwrap());
+            }
+        }
+        (state.cpu.iters - iters, state.cpu.cycles - cycles)
+    });
+}
+
+#[bench]
+fn bench_alu(b: &mut Bencher) {
+    mix("alu", ALU, false, b)
+}
+
+#[bench]
+fn bench_alu_cached(b: &mut Bencher) {
+    mix("alu, block cache", ALU, true, b)
+}
+
+#[bench]
+fn bench_memory(b: &mut Bencher) {
+    mix("memory", MEMORY, false, b)
+}
+
+#[bench]
+fn bench_memory_cached(b: &mut Bencher) {
+    mix("memory, block cache", MEMORY, true, b)
+}
+
+#[bench]
+fn bench_branch(b: &mut Bencher) {
+    mix("branch", BRANCH, false, b)
+}
+
+#[bench]
+fn bench_branch_cached(b: &mut Bencher) {
+    mix("branch, block cache", BRANCH, true, b)
+}
+
+/// Boots Space Invaders and runs its first `FRAMES` frames on a simulated clock, so the
+/// interrupts land on the same instructions every time.
+fn invaders_attract(name: &str, cached: bool, b: &mut Bencher) {
+    if !Path::new(INVADERS_ROM).exists() {
+        println!("{} not found, skipping", INVADERS_ROM);
+        return;
+    }
+    measure(name, b, || {
+        let mut machine =
+            Machine::<MidwayInterface<SpaceInvaders>>::load::<SpaceInvaders, _>(INVADERS_ROM)
+                .unwrap();
+        if cached {
+            machine.enable_block_cache();
+        }
+        let mut now = Instant::now();
+        for _ in 0..FRAMES * 2 {
+            now += Duration::from_micros(HALF_FRAME_MICROS);
+            machine.step(&now, HALF_FRAME_CYCLES).unwrap();
+        }
+        (machine.cpu().iters, machine.cpu().cycles)
+    });
+}
+
+#[bench]
+fn bench_invaders_attract(b: &mut Bencher) {
+    invaders_attract("invaders attract mode", false, b)
+}
+
+#[bench]
+fn bench_invaders_attract_cached(b: &mut Bencher) {
+    invaders_attract("invaders attract mode, block cache", true, b)
+}
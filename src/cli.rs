@@ -0,0 +1,348 @@
+//! Command-line arguments: a subcommand, then an optional game name and flags in any order.
+
+use crate::machine::memory::WritePolicy;
+use crate::machine::{Error, Machine, MachineInterface};
+use crate::midway;
+use std::path::PathBuf;
+
+const USAGE: &str = "usage: emulator [command] [game] [flags]
+
+commands:
+    run [game]              play a game, Space Invaders by default (the default command)
+    disassemble [game]      print a listing of a game's ROM
+    diag                    run the cpudiag CPU exerciser in the terminal
+    replay <movie> [game]   play back recorded input
+    help                    show this message
+
+flags:
+    --rom <path>            ROM file, zip or directory, instead of roms/<game>
+    --scale <n>             draw each pixel n times as large
+    --headless              run without a window; a headless replay stops when the movie ends
+    --trace <file>          write every executed instruction to file
+    --speed <x>             run x times as fast as the real hardware
+    --debug                 stop before every instruction and wait for enter
+    --block-cache           run through the basic block cache, for speed
+    --cheats <file>         force values from a file of <address> <value> [once] lines
+    --search                search RAM by typing new, changed, unchanged or a hex value
+    --symbols <file>        label disassembly, traces and cpu errors from a symbol file
+    --coverage <file>       record memory coverage, written to file.coverage and file.hints
+    --history <n>           instructions a cpu error shows, 256 by default and 0 for none
+    --rom-writes <policy>   ignore, log or error on writes to ROM instead of the game's default
+    --hints <file>          disassemble with code and data hints, e.g. from a coverage run
+    --output <file>         write the listing to file instead of stdout
+
+keys:
+    P pause, N one frame while paused, Tab 4x speed while held, - slower, = faster
+";
+
+const RUN_FLAGS: &[&str] = &[
+    "--rom",
+    "--scale",
+    "--headless",
+    "--trace",
+    "--speed",
+    "--debug",
+    "--block-cache",
+    "--cheats",
+    "--search",
+    "--symbols",
+    "--coverage",
+    "--history",
+    "--rom-writes",
+];
+const DISASSEMBLE_FLAGS: &[&str] = &["--rom", "--symbols", "--hints", "--output"];
+
+/// Usage and the list of games.
+pub fn usage() -> String {
+    format!("{}\ngames:\n{}", USAGE, midway::list())
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run(String, Options),
+    Disassemble(String, Options),
+    Diag(Options),
+    /// Like `Run`, with `Options::movie` set.
+    Replay(String, Options),
+    Help,
+}
+
+/// Flags, with whatever wasn't given at its default.
+#[derive(Debug, PartialEq)]
+pub struct Options {
+    pub rom: Option<PathBuf>,
+    pub scale: u32,
+    pub headless: bool,
+    pub trace: Option<PathBuf>,
+    pub speed: f64,
+    pub debug: bool,
+    pub block_cache: bool,
+    pub movie: Option<PathBuf>,
+    pub cheats: Option<PathBuf>,
+    pub search: bool,
+    pub symbols: Option<PathBuf>,
+    pub coverage: Option<PathBuf>,
+    pub history: Option<usize>,
+    pub rom_writes: Option<WritePolicy>,
+    pub hints: Option<PathBuf>,
+    pub output: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            rom: None,
+            scale: 1,
+            headless: false,
+            trace: None,
+            speed: 1.0,
+            debug: false,
+            block_cache: false,
+            movie: None,
+            cheats: None,
+            search: false,
+            symbols: None,
+            coverage: None,
+            history: None,
+            rom_writes: None,
+            hints: None,
+            output: None,
+        }
+    }
+}
+
+impl Options {
+    /// `--rom`, or where `game`'s ROMs are by default.
+    pub fn rom_path(&self, game: &str) -> PathBuf {
+        self.rom
+            .clone()
+            .unwrap_or_else(|| midway::default_path(game))
+    }
+
+    /// Sets `machine` up for the run flags.
+    pub fn apply<I: MachineInterface + Send + 'static>(
+        &self,
+        machine: &mut Machine<I>,
+    ) -> Result<(), Error> {
+        // Only ever turned on here, so machines that are always headless stay that way.
+        if self.headless {
+            machine.set_headless(true);
+        }
+        machine.set_scale(self.scale);
+        machine.set_speed(self.speed);
+        if let Some(path) = &self.trace {
+            machine.trace_to(path)?;
+        }
+        if self.debug {
+            machine.start_in_debugger();
+        }
+        if self.block_cache {
+            machine.enable_block_cache();
+        }
+        if let Some(path) = &self.movie {
+            machine.replay(path)?;
+        }
+        if let Some(path) = &self.cheats {
+            machine.load_cheats(path)?;
+        }
+        if self.search {
+            machine.search_from_console();
+        }
+        if let Some(path) = &self.symbols {
+            machine.load_symbols(path)?;
+        }
+        if let Some(path) = &self.coverage {
+            machine.record_coverage(path);
+        }
+        if let Some(depth) = self.history {
+            machine.set_history_depth(depth);
+        }
+        if let Some(policy) = self.rom_writes {
+            machine.set_rom_write_policy(policy);
+        }
+        Ok(())
+    }
+}
+
+pub fn parse<A: IntoIterator<Item = String>>(args: A) -> Result<Command, String> {
+    let mut args = args.into_iter().peekable();
+    let command = match args.peek().map(String::as_str) {
+        Some("run") | Some("disassemble") | Some("diag") | Some("replay") | Some("help") => {
+            args.next().unwrap()
+        }
+        _ => "run".to_owned(),
+    };
+    if command == "help" {
+        return Ok(Command::Help);
+    }
+
+    let allowed = if command == "disassemble" {
+        DISASSEMBLE_FLAGS
+    } else {
+        RUN_FLAGS
+    };
+    let mut positional = vec![];
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        if !allowed.contains(&arg.as_str()) {
+            return Err(format!("{} doesn't take {}", command, arg));
+        }
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--rom" => options.rom = Some(value()?.into()),
+            "--scale" => {
+                options.scale = match value()?.parse() {
+                    Ok(scale) if scale > 0 => scale,
+                    _ => return Err("--scale must be a whole number above 0".to_owned()),
+                }
+            }
+            "--headless" => options.headless = true,
+            "--trace" => options.trace = Some(value()?.into()),
+            "--speed" => {
+                options.speed = match value()?.parse::<f64>() {
+                    Ok(speed) if speed > 0.0 && speed.is_finite() => speed,
+                    _ => return Err("--speed must be a finite number above 0".to_owned()),
+                }
+            }
+            "--debug" => options.debug = true,
+            "--block-cache" => options.block_cache = true,
+            "--cheats" => options.cheats = Some(value()?.into()),
+            "--search" => options.search = true,
+            "--symbols" => options.symbols = Some(value()?.into()),
+            "--coverage" => options.coverage = Some(value()?.into()),
+            "--history" => {
+                options.history = match value()?.parse() {
+                    Ok(depth) => Some(depth),
+                    _ => return Err("--history must be a whole number".to_owned()),
+                }
+            }
+            "--rom-writes" => {
+                options.rom_writes = match value()?.as_str() {
+                    "ignore" => Some(WritePolicy::Ignore),
+                    "log" => Some(WritePolicy::Log),
+                    "error" => Some(WritePolicy::Error),
+                    _ => return Err("--rom-writes must be ignore, log or error".to_owned()),
+                }
+            }
+            "--hints" => options.hints = Some(value()?.into()),
+            "--output" => options.output = Some(value()?.into()),
+            _ => unreachable!(),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let game = |positional: &mut dyn Iterator<Item = String>| {
+        positional.next().unwrap_or_else(|| "invaders".to_owned())
+    };
+    let command = match command.as_str() {
+        "run" => Command::Run(game(&mut positional), options),
+        "disassemble" => Command::Disassemble(game(&mut positional), options),
+        "diag" => Command::Diag(options),
+        "replay" => {
+            options.movie = match positional.next() {
+                Some(movie) => Some(movie.into()),
+                None => return Err("replay needs a movie file".to_owned()),
+            };
+            Command::Replay(game(&mut positional), options)
+        }
+        _ => unreachable!(),
+    };
+    match positional.next() {
+        Some(extra) => Err(format!("unexpected argument {}", extra)),
+        None => Ok(command),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Command, String> {
+        parse(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_str(""),
+            Ok(Command::Run("invaders".to_owned(), Options::default()))
+        );
+        assert_eq!(
+            parse_str("run seawolf --scale 3 --headless --speed 0.5 --block-cache"),
+            Ok(Command::Run(
+                "seawolf".to_owned(),
+                Options {
+                    scale: 3,
+                    headless: true,
+                    block_cache: true,
+                    speed: 0.5,
+                    ..Options::default()
+                }
+            ))
+        );
+        assert_eq!(
+            parse_str("gunfight --rom gf.zip --debug --cheats gf.cht --search"),
+            Ok(Command::Run(
+                "gunfight".to_owned(),
+                Options {
+                    rom: Some("gf.zip".into()),
+                    debug: true,
+                    cheats: Some("gf.cht".into()),
+                    search: true,
+                    ..Options::default()
+                }
+            ))
+        );
+        assert_eq!(
+            parse_str("--symbols si.sym --coverage si --history 0 --rom-writes error"),
+            Ok(Command::Run(
+                "invaders".to_owned(),
+                Options {
+                    symbols: Some("si.sym".into()),
+                    coverage: Some("si".into()),
+                    history: Some(0),
+                    rom_writes: Some(WritePolicy::Error),
+                    ..Options::default()
+                }
+            ))
+        );
+        assert_eq!(
+            parse_str("replay coin.movie --headless"),
+            Ok(Command::Replay(
+                "invaders".to_owned(),
+                Options {
+                    headless: true,
+                    movie: Some("coin.movie".into()),
+                    ..Options::default()
+                }
+            ))
+        );
+        match parse_str("disassemble --symbols si.sym --output si.asm") {
+            Ok(Command::Disassemble(game, options)) => {
+                assert_eq!(game, "invaders");
+                assert_eq!(options.symbols, Some("si.sym".into()));
+                assert_eq!(options.output, Some("si.asm".into()));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(parse_str("help --rom x"), Ok(Command::Help));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_str("run --scale 0").is_err());
+        assert!(parse_str("run --speed fast").is_err());
+        assert!(parse_str("run --speed inf").is_err());
+        assert!(parse_str("run --trace").is_err());
+        assert!(parse_str("run --output x").is_err());
+        assert!(parse_str("run --history -1").is_err());
+        assert!(parse_str("run --rom-writes warn").is_err());
+        assert!(parse_str("disassemble --headless").is_err());
+        assert!(parse_str("replay").is_err());
+        assert!(parse_str("run invaders extra").is_err());
+    }
+}
@@ -1,66 +1,62 @@
-use crate::machine::display;
-use crate::machine::memory;
-use crate::machine::memory::Memory;
+use crate::cli::Options;
+use crate::machine::memory_map::MemoryMap;
 use crate::machine::rom::Rom;
 use crate::machine::CPUInterface;
 use crate::machine::MachineEvent;
-use crate::machine::{Error, MachineInterface};
-use crossbeam_channel::Sender;
+use crate::machine::{Error, Machine, MachineInterface};
 use std::fs;
 use std::path::Path;
-use std::sync::Arc;
-use std::sync::RwLock;
-use std::sync::RwLockWriteGuard;
 use std::time::Instant;
 
-#[allow(dead_code)]
-struct Diag;
+/// Where cpudiag is looked for by default.
+pub const DEFAULT_PATH: &str = "roms/cpudiag.bin";
 
-#[derive(Clone)]
-pub struct DiagInterface {
-    memory: Arc<RwLock<Memory>>,
+struct Diag;
 
-    sender: Sender<[u8; display::FB_SIZE]>,
-}
+pub struct DiagInterface;
 
 impl MachineInterface for DiagInterface {
-    fn handle_in(&self, _cpu: &'_ mut CPUInterface<'_>, _port: u8) -> Result<(), Error> {
+    fn handle_in(&mut self, _cpu: &'_ mut CPUInterface<'_>, _port: u8) -> Result<(), Error> {
         Ok(())
     }
 
-    fn handle_out(&self, _cpu: &'_ mut CPUInterface<'_>, _port: u8) -> Result<(), Error> {
+    fn handle_out(&mut self, _cpu: &'_ mut CPUInterface<'_>, _port: u8) -> Result<(), Error> {
         Ok(())
     }
 
     fn handle_interrupt(
-        &self,
+        &mut self,
         _now: &'_ Instant,
         _cpu: &'_ mut CPUInterface<'_>,
     ) -> Result<(), Error> {
         Ok(())
     }
 
-    fn memory_handle(&self) -> Result<RwLockWriteGuard<'_, Memory>, Error> {
-        self.memory
-            .write()
-            .map_err(|_| Error::MemoryError(memory::Error::LockErr))
+    fn handle_event(&mut self, _evt: MachineEvent) -> Result<(), Error> {
+        unimplemented!()
     }
 
-    fn handle_event(&self, _evt: MachineEvent) -> Result<(), Error> {
-        unimplemented!()
+    fn memory_map() -> MemoryMap {
+        MemoryMap::flat()
     }
 
-    fn display_refresh(&self, _buf: [u8; display::FB_SIZE]) {}
-    fn apply(memory: Arc<RwLock<Memory>>, sender: Sender<[u8; display::FB_SIZE]>) -> Self
+    fn new() -> Self
     where
         Self: Sized,
     {
-        DiagInterface { memory, sender }
+        DiagInterface
     }
 }
 
 impl Rom<DiagInterface> for Diag {
     const DEBUG: bool = true;
+
+    /// cpudiag keeps its stack and scratch bytes in between its own code, so none of it is
+    /// protected.
+    fn rom_regions() -> Vec<(u16, u16)> {
+        vec![]
+    }
+
     fn load<P: AsRef<Path>>(p: P) -> Result<Vec<u8>, String> {
         use std::io::Read;
         let mut fd = fs::File::open(p).map_err(|_| "bad rom path")?;
@@ -80,13 +76,50 @@ impl Rom<DiagInterface> for Diag {
     }
 }
 
+/// Runs cpudiag from `path`, printing its results to the terminal.
+pub fn run<P: AsRef<Path>>(path: P, options: &Options) -> Result<(), Error> {
+    let mut machine = Machine::load::<Diag, _>(path).map_err(Error::ForeignError)?;
+    options.apply(&mut machine)?;
+    machine.run()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::diag;
+    use crate::machine::Machine;
+    use std::path::Path;
+    use std::time::Instant;
+
+    /// Runs cpudiag until it exits, through the block cache or not, and returns the final cpu
+    /// state and what it printed.
+    fn run_to_exit(cached: bool) -> (String, String) {
+        let mut machine = Machine::load::<diag::Diag, _>(diag::DEFAULT_PATH).unwrap();
+        if cached {
+            machine.enable_block_cache();
+        }
+        let now = Instant::now();
+        for _ in 0..1000 {
+            if machine.step(&now, 10_000).is_err() {
+                break;
+            }
+        }
+        let cpu = machine.cpu();
+        (cpu.to_string(), cpu.output.clone())
+    }
+
+    #[test]
+    fn test_block_cache() {
+        if !Path::new(diag::DEFAULT_PATH).exists() {
+            return;
+        }
+        let (cpu, output) = run_to_exit(false);
+        assert!(output.contains("CPU IS OPERATIONAL"), "{}", output);
+        assert_eq!(run_to_exit(true), (cpu, output));
+    }
 
     #[test]
     fn test_diag() {
-        match crate::machine::Machine::load::<diag::Diag>("roms/cpudiag.bin")
+        match crate::machine::Machine::load::<diag::Diag, _>(diag::DEFAULT_PATH)
             .expect("couldn't load rom")
             .run()
         {
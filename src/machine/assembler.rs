@@ -0,0 +1,654 @@
+use crate::machine::cpu::disassembler::disassemble;
+use crate::machine::cpu::ops::OpCode;
+use crate::machine::memory::Memory;
+use num::FromPrimitive;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Fail, Debug)]
+pub enum Error {
+    #[fail(display = "line {}: syntax error: {}", _0, _1)]
+    Syntax(usize, String),
+
+    #[fail(display = "line {}: undefined symbol: {}", _0, _1)]
+    UndefinedSymbol(usize, String),
+
+    #[fail(display = "line {}: duplicate symbol: {}", _0, _1)]
+    DuplicateSymbol(usize, String),
+
+    #[fail(display = "line {}: unknown instruction: {}", _0, _1)]
+    UnknownInstruction(usize, String),
+
+    #[fail(display = "line {}: value out of range: {}", _0, _1)]
+    OutOfRange(usize, i64),
+
+    #[fail(display = "IoError {}", _0)]
+    IoError(#[fail(cause)] io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
+lazy_static! {
+    /// Opcodes by name, e.g. `MOV_B_C` or `LXI_SP`, leaving out the undocumented aliases.
+    static ref OPCODES: HashMap<String, u8> = {
+        let mut map = HashMap::new();
+        for code in 0..=255u8 {
+            match OpCode::from_u8(code) {
+                Some(OpCode::NOP_0) => map.insert("NOP".to_owned(), code),
+                Some(op) if !op.is_alias() => map.insert(format!("{:?}", op), code),
+                _ => None,
+            };
+        }
+        map
+    };
+
+    static ref LENGTHS: Vec<u16> = (0..=255u8)
+        .map(|code| {
+            disassemble(&Memory::new(vec![code, 0, 0]), 0)
+                .map(|(_, inc)| inc)
+                .unwrap_or(1)
+        })
+        .collect();
+}
+
+const REGISTERS: [&str; 10] = ["A", "B", "C", "D", "E", "H", "L", "M", "SP", "PSW"];
+const DIRECTIVES: [&str; 6] = ["ORG", "EQU", "SET", "DB", "DW", "DS"];
+
+/// The output of `assemble`: a binary image starting at `origin` and a listing.
+#[derive(Debug)]
+pub struct Assembly {
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub listing: String,
+}
+
+impl Assembly {
+    pub fn write_binary<P: AsRef<Path>>(&self, p: P) -> Result<(), Error> {
+        Ok(fs::write(p, &self.bytes)?)
+    }
+
+    pub fn write_listing<P: AsRef<Path>>(&self, p: P) -> Result<(), Error> {
+        Ok(fs::write(p, &self.listing)?)
+    }
+}
+
+struct Line<'a> {
+    number: usize,
+    text: &'a str,
+    label: Option<&'a str>,
+    op: Option<String>,
+    operands: Vec<&'a str>,
+}
+
+fn is_keyword(s: &str) -> bool {
+    let s = s.to_uppercase();
+    DIRECTIVES.contains(&s.as_str())
+        || s == "RST"
+        || OPCODES
+            .keys()
+            .any(|k| k == &s || k.starts_with(&format!("{}_", s)))
+}
+
+/// Drops a trailing `;` comment, ignoring semicolons inside quotes.
+fn strip_comment(s: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &s[..i],
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => (),
+        }
+    }
+    s
+}
+
+/// Splits operands on commas that aren't inside quotes.
+fn split_operands(s: &str) -> Vec<&str> {
+    let mut operands = vec![];
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                operands.push(s[start..i].trim());
+                start = i + 1;
+            }
+            (None, '\'') | (None, '"') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => (),
+        }
+    }
+    if !s[start..].trim().is_empty() || !operands.is_empty() {
+        operands.push(s[start..].trim());
+    }
+    operands
+}
+
+fn next_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+fn parse_line(number: usize, text: &str) -> Line {
+    let code = strip_comment(text).trim_end();
+    let mut line = Line {
+        number,
+        text,
+        label: None,
+        op: None,
+        operands: vec![],
+    };
+    if code.trim().is_empty() {
+        return line;
+    }
+
+    let mut rest = code;
+    let (first, after) = next_word(code);
+    let starts_in_column_0 = !code.starts_with(char::is_whitespace);
+    if first.ends_with(':') {
+        line.label = Some(first.trim_end_matches(':'));
+        rest = after;
+    } else if starts_in_column_0 && !is_keyword(first) {
+        line.label = Some(first);
+        rest = after;
+    }
+
+    let (op, operands) = next_word(rest);
+    if !op.is_empty() {
+        line.op = Some(op.to_uppercase());
+        line.operands = split_operands(operands);
+    }
+    line
+}
+
+/// Evaluates assembler expressions: numbers in `$1F`, `0x1F`, `1FH`, `101B` and decimal
+/// forms, character constants, symbols, `$` for the current address, parentheses and the
+/// usual arithmetic and bitwise operators.
+struct Expr<'a, 'b> {
+    s: &'a [u8],
+    pos: usize,
+    here: u16,
+    symbols: &'b HashMap<String, u16>,
+}
+
+impl<'a, 'b> Expr<'a, 'b> {
+    fn eval(
+        s: &'a str,
+        here: u16,
+        symbols: &'b HashMap<String, u16>,
+    ) -> Result<i64, Result<String, String>> {
+        let mut expr = Expr {
+            s: s.as_bytes(),
+            pos: 0,
+            here,
+            symbols,
+        };
+        let v = expr.binary(0)?;
+        expr.skip_ws();
+        if expr.pos != expr.s.len() {
+            return Err(Err(format!("unexpected `{}`", &s[expr.pos..])));
+        }
+        Ok(v)
+    }
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.s.len() && self.s[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek_op(&mut self) -> Option<(&'static str, u8)> {
+        self.skip_ws();
+        let rest = &self.s[self.pos..];
+        let ops: [(&'static str, u8); 10] = [
+            ("|", 1),
+            ("^", 2),
+            ("&", 3),
+            ("<<", 4),
+            (">>", 4),
+            ("+", 5),
+            ("-", 5),
+            ("*", 6),
+            ("/", 6),
+            ("%", 6),
+        ];
+        ops.iter()
+            .find(|(op, _)| rest.starts_with(op.as_bytes()))
+            .cloned()
+    }
+
+    /// Precedence climbing; `Err(Ok(name))` is an undefined symbol, `Err(Err(msg))` a syntax
+    /// error.
+    fn binary(&mut self, min: u8) -> Result<i64, Result<String, String>> {
+        let mut lhs = self.unary()?;
+        while let Some((op, prec)) = self.peek_op() {
+            if prec <= min {
+                break;
+            }
+            self.pos += op.len();
+            let rhs = self.binary(prec)?;
+            lhs = match op {
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "<<" => lhs << (rhs & 0x3f),
+                ">>" => lhs >> (rhs & 0x3f),
+                "+" => lhs + rhs,
+                "-" => lhs - rhs,
+                "*" => lhs * rhs,
+                "/" | "%" if rhs == 0 => return Err(Err("division by zero".to_owned())),
+                "/" => lhs / rhs,
+                _ => lhs % rhs,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<i64, Result<String, String>> {
+        self.skip_ws();
+        match self.s.get(self.pos) {
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(-self.unary()?)
+            }
+            Some(b'+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            Some(b'~') => {
+                self.pos += 1;
+                Ok(!self.unary()?)
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                let v = self.binary(0)?;
+                self.skip_ws();
+                if self.s.get(self.pos) != Some(&b')') {
+                    return Err(Err("missing `)`".to_owned()));
+                }
+                self.pos += 1;
+                Ok(v)
+            }
+            Some(b'\'') if self.s.get(self.pos + 2) == Some(&b'\'') => {
+                let c = self.s[self.pos + 1];
+                self.pos += 3;
+                Ok(i64::from(c))
+            }
+            Some(b'$') => {
+                self.pos += 1;
+                let word = self.word();
+                if word.is_empty() {
+                    Ok(i64::from(self.here))
+                } else {
+                    i64::from_str_radix(word, 16)
+                        .map_err(|_| Err(format!("bad hex number `${}`", word)))
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let word = self.word();
+                number(word).ok_or_else(|| Err(format!("bad number `{}`", word)))
+            }
+            Some(_) => {
+                let word = self.word();
+                if word.is_empty() {
+                    return Err(Err("expected an expression".to_owned()));
+                }
+                self.symbols
+                    .get(word)
+                    .map(|v| i64::from(*v))
+                    .ok_or_else(|| Ok(word.to_owned()))
+            }
+            None => Err(Err("expected an expression".to_owned())),
+        }
+    }
+
+    fn word(&mut self) -> &'a str {
+        let start = self.pos;
+        while self.pos < self.s.len() {
+            let c = self.s[self.pos];
+            if c.is_ascii_alphanumeric() || c == b'_' || c == b'?' || c == b'@' || c == b'.' {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        ::std::str::from_utf8(&self.s[start..self.pos]).unwrap()
+    }
+}
+
+fn number(word: &str) -> Option<i64> {
+    let upper = word.to_uppercase();
+    if upper.starts_with("0X") {
+        i64::from_str_radix(&upper[2..], 16).ok()
+    } else if upper.ends_with('H') {
+        i64::from_str_radix(&upper[..upper.len() - 1], 16).ok()
+    } else if upper.ends_with('B') {
+        i64::from_str_radix(&upper[..upper.len() - 1], 2).ok()
+    } else if upper.ends_with('D') {
+        upper[..upper.len() - 1].parse().ok()
+    } else {
+        upper.parse().ok()
+    }
+}
+
+fn quoted(s: &str) -> Option<&str> {
+    if s.len() >= 2
+        && ((s.starts_with('\'') && s.ends_with('\'')) || (s.starts_with('"') && s.ends_with('"')))
+    {
+        Some(&s[1..s.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// Works out the opcode for `op` by appending register operands to the mnemonic, e.g.
+/// `MOV B,C` is `MOV_B_C`. Returns the opcode and the remaining, non-register operands.
+fn resolve<'a>(line: &Line<'a>, op: &str) -> Result<(u8, Vec<&'a str>), Error> {
+    let mut key = op.to_owned();
+    let mut operands = line.operands.iter().cloned().peekable();
+    while let Some(operand) = operands.peek() {
+        let reg = operand.to_uppercase();
+        let candidate = format!("{}_{}", key, reg);
+        let prefix = format!("{}_", candidate);
+        if REGISTERS.contains(&reg.as_str())
+            && (OPCODES.contains_key(&candidate) || OPCODES.keys().any(|k| k.starts_with(&prefix)))
+        {
+            key = candidate;
+            operands.next();
+        } else {
+            break;
+        }
+    }
+    match OPCODES.get(&key) {
+        Some(code) => Ok((*code, operands.collect())),
+        None => Err(Error::UnknownInstruction(
+            line.number,
+            line.text.trim().to_owned(),
+        )),
+    }
+}
+
+fn size(line: &Line, here: u16, symbols: &HashMap<String, u16>) -> Result<u16, Error> {
+    let op = match &line.op {
+        Some(op) => op.as_str(),
+        None => return Ok(0),
+    };
+    Ok(match op {
+        "ORG" | "EQU" | "SET" => 0,
+        "DB" => line
+            .operands
+            .iter()
+            .map(|o| quoted(o).map_or(1, |s| s.len() as u16))
+            .sum(),
+        "DW" => 2 * line.operands.len() as u16,
+        "DS" => count(line, value(line, line.operands.first(), here, symbols)?)?,
+        "RST" => 1,
+        op => LENGTHS[resolve(line, op)?.0 as usize],
+    })
+}
+
+fn value(
+    line: &Line,
+    operand: Option<&&str>,
+    here: u16,
+    symbols: &HashMap<String, u16>,
+) -> Result<i64, Error> {
+    let operand =
+        operand.ok_or_else(|| Error::Syntax(line.number, "missing operand".to_owned()))?;
+    Expr::eval(operand, here, symbols).map_err(|e| match e {
+        Ok(name) => Error::UndefinedSymbol(line.number, name),
+        Err(msg) => Error::Syntax(line.number, msg),
+    })
+}
+
+fn byte(line: &Line, v: i64) -> Result<u8, Error> {
+    if !(-128..=255).contains(&v) {
+        Err(Error::OutOfRange(line.number, v))
+    } else {
+        Ok((v & 0xff) as u8)
+    }
+}
+
+fn word(line: &Line, v: i64) -> Result<[u8; 2], Error> {
+    if !(-32768..=65535).contains(&v) {
+        Err(Error::OutOfRange(line.number, v))
+    } else {
+        Ok([(v & 0xff) as u8, ((v >> 8) & 0xff) as u8])
+    }
+}
+
+/// A `DS` byte count.
+fn count(line: &Line, v: i64) -> Result<u16, Error> {
+    if !(0..=0xffff).contains(&v) {
+        Err(Error::OutOfRange(line.number, v))
+    } else {
+        Ok(v as u16)
+    }
+}
+
+fn encode(line: &Line, here: u16, symbols: &HashMap<String, u16>) -> Result<Vec<u8>, Error> {
+    let op = match &line.op {
+        Some(op) => op.as_str(),
+        None => return Ok(vec![]),
+    };
+    let mut bytes = vec![];
+    match op {
+        "ORG" | "EQU" | "SET" => (),
+        "DB" => {
+            for operand in &line.operands {
+                match quoted(operand) {
+                    Some(s) => bytes.extend(s.bytes()),
+                    None => bytes.push(byte(line, value(line, Some(operand), here, symbols)?)?),
+                }
+            }
+        }
+        "DW" => {
+            for operand in &line.operands {
+                bytes.extend(&word(line, value(line, Some(operand), here, symbols)?)?);
+            }
+        }
+        "DS" => bytes.resize(
+            count(line, value(line, line.operands.first(), here, symbols)?)?.into(),
+            0,
+        ),
+        "RST" => {
+            let n = value(line, line.operands.first(), here, symbols)?;
+            if !(0..=7).contains(&n) {
+                return Err(Error::OutOfRange(line.number, n));
+            }
+            bytes.push(0xc7 | (n as u8) << 3);
+        }
+        op => {
+            let (code, operands) = resolve(line, op)?;
+            bytes.push(code);
+            let len = LENGTHS[code as usize];
+            if operands.len() != if len == 1 { 0 } else { 1 } {
+                return Err(Error::Syntax(
+                    line.number,
+                    format!("wrong number of operands for {}", op),
+                ));
+            }
+            match len {
+                2 => bytes.push(byte(line, value(line, operands.first(), here, symbols)?)?),
+                3 => bytes.extend(&word(line, value(line, operands.first(), here, symbols)?)?),
+                _ => (),
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+/// Evaluates a constant expression such as `$2400+2`, with `$` standing for address 0.
+pub fn evaluate(s: &str) -> Option<i64> {
+    Expr::eval(s, 0, &HashMap::new()).ok()
+}
+
+/// Assembles Intel 8080 source in two passes, the first to assign addresses to labels and
+/// the second to emit bytes.
+pub fn assemble(source: &str) -> Result<Assembly, Error> {
+    let lines: Vec<Line> = source
+        .lines()
+        .enumerate()
+        .map(|(i, text)| parse_line(i + 1, text))
+        .collect();
+
+    let mut symbols = HashMap::new();
+    let mut here: u16 = 0;
+    for line in &lines {
+        let op = line.op.as_ref().map(|s| s.as_str());
+        if let Some(label) = line.label {
+            let v = match op {
+                Some("EQU") | Some("SET") => {
+                    value(line, line.operands.first(), here, &symbols)? as u16
+                }
+                _ => here,
+            };
+            if symbols.insert(label.to_owned(), v).is_some() && op != Some("SET") {
+                return Err(Error::DuplicateSymbol(line.number, label.to_owned()));
+            }
+        }
+        if op == Some("ORG") {
+            here = value(line, line.operands.first(), here, &symbols)? as u16;
+        }
+        here = here.wrapping_add(size(line, here, &symbols)?);
+    }
+
+    let mut image: Vec<(u16, u8)> = vec![];
+    let mut listing = String::new();
+    here = 0;
+    for line in &lines {
+        if line.op.as_ref().map(|s| s.as_str()) == Some("ORG") {
+            here = value(line, line.operands.first(), here, &symbols)? as u16;
+        }
+        let bytes = encode(line, here, &symbols)?;
+        let shown: Vec<String> = bytes.iter().take(4).map(|b| format!("{:02X}", b)).collect();
+        listing.push_str(&format!(
+            "{:5} {:04X}  {:<12}{}\n",
+            line.number,
+            here,
+            shown.join(" "),
+            line.text
+        ));
+        for b in bytes {
+            image.push((here, b));
+            here = here.wrapping_add(1);
+        }
+    }
+
+    let origin = image.iter().map(|(a, _)| *a).min().unwrap_or(0);
+    let end = image
+        .iter()
+        .map(|(a, _)| *a as usize + 1)
+        .max()
+        .unwrap_or(0);
+    let mut bytes = vec![0; end.saturating_sub(origin as usize)];
+    for (addr, b) in image {
+        bytes[(addr - origin) as usize] = b;
+    }
+    Ok(Assembly {
+        origin,
+        bytes,
+        listing,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::cpu::syntax::{HexStyle, Syntax};
+    use crate::machine::cpu::testing;
+    use crate::machine::disassembly;
+    use crate::machine::symbols::Symbols;
+    use rand::Rng;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+COUNT   EQU 3
+        ORG 100H
+start:  LXI SP,$2400
+        MVI B,COUNT*2
+loop:   DCR B
+        JNZ loop
+        CALL done
+        RST 7
+        DB 'Hi', 0, -1
+        DW start, $ + 2
+done:   RET
+        DS 2
+";
+        let assembly = assemble(source).unwrap();
+        assert_eq!(assembly.origin, 0x100);
+        assert_eq!(
+            assembly.bytes,
+            vec![
+                0x31, 0x00, 0x24, 0x06, 0x06, 0x05, 0xc2, 0x05, 0x01, 0xcd, 0x15, 0x01, 0xff, b'H',
+                b'i', 0x00, 0xff, 0x00, 0x01, 0x13, 0x01, 0xc9, 0x00, 0x00,
+            ]
+        );
+        assert!(assembly.listing.contains("0105  05"));
+    }
+
+    #[test]
+    fn test_errors() {
+        match assemble("  JMP nowhere") {
+            Err(Error::UndefinedSymbol(1, ref name)) if name == "nowhere" => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        match assemble("\n  MOV A") {
+            Err(Error::UnknownInstruction(2, _)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        match assemble("  MVI A,300") {
+            Err(Error::OutOfRange(1, 300)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        match assemble("  DS -1") {
+            Err(Error::OutOfRange(1, -1)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        match assemble("  DS $10000") {
+            Err(Error::OutOfRange(1, 0x10000)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = testing::rng();
+        let syntaxes = [
+            Syntax::default(),
+            Syntax {
+                hex: HexStyle::Suffix,
+                uppercase: false,
+            },
+            Syntax {
+                hex: HexStyle::Prefix,
+                uppercase: true,
+            },
+        ];
+        for syntax in syntaxes.iter() {
+            for _ in 0..10 {
+                let rom: Vec<u8> = (0..0x800).map(|_| rng.gen()).collect();
+                let mem = Memory::new(rom.clone());
+                let (seeds, data) = disassembly::seeds(&[]);
+                let map = disassembly::trace(&mem, &seeds, &data);
+                let source = disassembly::listing(&mem, &map, &mut Symbols::new(), syntax);
+
+                let assembly = assemble(&source).unwrap();
+                assert_eq!(assembly.origin, 0);
+                assert_eq!(assembly.bytes, rom);
+            }
+        }
+    }
+}
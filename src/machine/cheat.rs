@@ -0,0 +1,308 @@
+//! Finding and forcing game variables. A `Search` narrows RAM down to the addresses that hold
+//! a value or that did or didn't change since the last look, e.g. the lives counter after
+//! losing a life. `Cheats` then keep values written there, through `Memory::write` like any
+//! other write.
+//!
+//! While a game runs, `console` reads search commands from the terminal, a line each: `new`
+//! starts over, a hex value keeps the addresses holding it, and `changed` and `unchanged`
+//! compare with the last command.
+//!
+//! A cheat file has a line per cheat: the address and value in hex, then `once` to write it
+//! a single time instead of every frame, right after the game first writes the address
+//! itself so its own setup doesn't undo the cheat. `;` starts a comment.
+//!
+//! ```text
+//! 21FF 03       ; infinite lives
+//! $2067 05 once ; start with five ships
+//! ```
+
+use crate::machine::memory::{self, Memory};
+use crate::machine::{Controller, MachineEvent};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::thread;
+
+/// Candidates listed after each search command, at most.
+const LISTED: usize = 16;
+
+/// What a `Search` keeps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Equal(u8),
+    Changed,
+    Unchanged,
+}
+
+/// A line typed at the search console.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchCommand {
+    /// Starts over with all of RAM.
+    New,
+    Filter(Filter),
+}
+
+impl SearchCommand {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim() {
+            "new" => Some(SearchCommand::New),
+            "changed" => Some(SearchCommand::Filter(Filter::Changed)),
+            "unchanged" => Some(SearchCommand::Filter(Filter::Unchanged)),
+            value => {
+                let value = value.trim_start_matches('$').trim_start_matches("0x");
+                u8::from_str_radix(value, 16)
+                    .ok()
+                    .map(|value| SearchCommand::Filter(Filter::Equal(value)))
+            }
+        }
+    }
+}
+
+/// Reads search commands from stdin on a thread of its own and sends them to the machine.
+pub fn console(controller: Controller) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            match SearchCommand::parse(&line) {
+                Some(command) => controller.send(MachineEvent::Search(command)),
+                None => eprintln!("search: new, changed, unchanged or a hex value"),
+            }
+        }
+    });
+}
+
+/// Addresses still in the running, with the value each had at the last filter.
+pub struct Search {
+    candidates: Vec<(u16, u8)>,
+}
+
+impl Search {
+    /// Starts with every byte of `memory`'s RAM.
+    pub fn new(memory: &Memory) -> Result<Self, memory::Error> {
+        let mut candidates = vec![];
+        for (start, end) in memory.ram() {
+            for address in start..=end {
+                candidates.push((address, memory.read(address)?));
+            }
+        }
+        Ok(Search { candidates })
+    }
+
+    /// Drops the addresses that don't match `filter` now.
+    pub fn filter(&mut self, memory: &Memory, filter: Filter) -> Result<(), memory::Error> {
+        let mut kept = vec![];
+        for &(address, last) in &self.candidates {
+            let value = memory.read(address)?;
+            let keep = match filter {
+                Filter::Equal(wanted) => value == wanted,
+                Filter::Changed => value != last,
+                Filter::Unchanged => value == last,
+            };
+            if keep {
+                kept.push((address, value));
+            }
+        }
+        self.candidates = kept;
+        Ok(())
+    }
+
+    pub fn candidates(&self) -> &[(u16, u8)] {
+        &self.candidates
+    }
+
+    /// How many candidates are left and, if few enough, what they are.
+    pub fn summary(&self) -> String {
+        let mut s = format!("{} candidates", self.candidates.len());
+        if self.candidates.len() <= LISTED {
+            for (address, value) in &self.candidates {
+                s.push_str(&format!("\n  ${:04X} = {:02X}", address, value));
+            }
+        }
+        s
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    /// Written once, at the first frame after the game has written the address, rather than
+    /// every frame.
+    pub once: bool,
+}
+
+#[derive(Debug)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    /// `Memory::watch_writes` ids for the `once` cheats still to be written, by index into
+    /// `cheats`.
+    watches: Vec<(usize, usize)>,
+    started: bool,
+}
+
+impl Cheats {
+    pub fn new(cheats: Vec<Cheat>) -> Self {
+        Cheats {
+            cheats,
+            watches: vec![],
+            started: false,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Self, String> {
+        let s = fs::read_to_string(p).map_err(|_| "failed to read cheat file".to_owned())?;
+        Self::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut cheats = vec![];
+        for (n, line) in s.lines().enumerate() {
+            let line = match line.find(';') {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.is_empty() {
+                continue;
+            }
+            if parts.len() < 2 || parts.len() > 3 {
+                return Err(format!("bad cheat on line {}: {}", n + 1, line.trim()));
+            }
+            let address = parts[0].trim_start_matches('$').trim_start_matches("0x");
+            let address = u16::from_str_radix(address, 16)
+                .map_err(|_| format!("bad address on line {}: {}", n + 1, parts[0]))?;
+            let value = u8::from_str_radix(parts[1].trim_start_matches('$'), 16)
+                .map_err(|_| format!("bad value on line {}: {}", n + 1, parts[1]))?;
+            let once = match parts.get(2) {
+                None => false,
+                Some(&"once") => true,
+                Some(other) => return Err(format!("unknown flag on line {}: {}", n + 1, other)),
+            };
+            cheats.push(Cheat {
+                address,
+                value,
+                once,
+            });
+        }
+        Ok(Cheats::new(cheats))
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    /// Writes the cheats due this frame. Call at the start of every frame, from before the
+    /// game first runs.
+    pub fn apply(&mut self, memory: &mut Memory) -> Result<(), memory::Error> {
+        if !self.started {
+            for (i, cheat) in self.cheats.iter().enumerate() {
+                if cheat.once {
+                    self.watches
+                        .push((i, memory.watch_writes(cheat.address, cheat.address)?));
+                }
+            }
+            self.started = true;
+        }
+
+        let mut waiting = vec![];
+        for &(i, id) in &self.watches {
+            if memory.all_written(id) {
+                memory.unwatch_writes(id);
+                memory.write(self.cheats[i].address, self.cheats[i].value)?;
+            } else {
+                waiting.push((i, id));
+            }
+        }
+        self.watches = waiting;
+
+        for cheat in &self.cheats {
+            if !cheat.once {
+                memory.write(cheat.address, cheat.value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::memory_map::{MemoryMap, RegionKind};
+
+    #[test]
+    fn test_search() {
+        let map = MemoryMap::new()
+            .region(0x0000, 0x00ff, RegionKind::Rom)
+            .region(0x0100, 0x02ff, RegionKind::Ram)
+            .region(0x0400, 0x07ff, RegionKind::Mirror(0x03ff));
+        let mut memory = Memory::with_map(&map).unwrap();
+        assert_eq!(memory.ram(), vec![(0x0100, 0x02ff)]);
+
+        memory.write(0x0110, 3).unwrap();
+        memory.write(0x0220, 3).unwrap();
+        let mut search = Search::new(&memory).unwrap();
+        search.filter(&memory, Filter::Equal(3)).unwrap();
+        assert_eq!(search.candidates(), &[(0x0110, 3), (0x0220, 3)]);
+
+        memory.write(0x0110, 2).unwrap();
+        search.filter(&memory, Filter::Changed).unwrap();
+        assert_eq!(search.candidates(), &[(0x0110, 2)]);
+        search.filter(&memory, Filter::Unchanged).unwrap();
+        assert_eq!(search.candidates(), &[(0x0110, 2)]);
+        memory.write(0x0110, 1).unwrap();
+        search.filter(&memory, Filter::Unchanged).unwrap();
+        assert!(search.candidates().is_empty());
+        assert_eq!(search.summary(), "0 candidates");
+
+        assert_eq!(SearchCommand::parse(" new"), Some(SearchCommand::New));
+        assert_eq!(
+            SearchCommand::parse("$0A"),
+            Some(SearchCommand::Filter(Filter::Equal(10)))
+        );
+        assert_eq!(
+            SearchCommand::parse("unchanged"),
+            Some(SearchCommand::Filter(Filter::Unchanged))
+        );
+        assert_eq!(SearchCommand::parse("lives"), None);
+    }
+
+    #[test]
+    fn test_cheats() {
+        let mut cheats = Cheats::parse(
+            "; lives
+            21FF 03
+            $2067 05 once ; wave",
+        )
+        .unwrap();
+        assert_eq!(
+            cheats.cheats()[1],
+            Cheat {
+                address: 0x2067,
+                value: 5,
+                once: true
+            }
+        );
+
+        let mut memory = Memory::with_map(&MemoryMap::flat()).unwrap();
+        cheats.apply(&mut memory).unwrap();
+        assert_eq!(memory.read(0x21ff).unwrap(), 3);
+        // Not before the game has set the address up itself.
+        assert_eq!(memory.read(0x2067).unwrap(), 0);
+        memory.write(0x21ff, 2).unwrap();
+        memory.write(0x2067, 3).unwrap();
+        cheats.apply(&mut memory).unwrap();
+        assert_eq!(memory.read(0x21ff).unwrap(), 3);
+        assert_eq!(memory.read(0x2067).unwrap(), 5);
+        memory.write(0x2067, 4).unwrap();
+        cheats.apply(&mut memory).unwrap();
+        assert_eq!(memory.read(0x2067).unwrap(), 4);
+
+        assert!(Cheats::parse("21FF").is_err());
+        assert!(Cheats::parse("21FF 100").is_err());
+        assert!(Cheats::parse("21FF 03 always").is_err());
+    }
+}
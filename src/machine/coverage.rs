@@ -0,0 +1,188 @@
+use std::fmt::Write;
+
+pub const OPCODE: u8 = 0x1;
+pub const OPERAND: u8 = 0x2;
+pub const READ: u8 = 0x4;
+pub const WRITE: u8 = 0x8;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Access {
+    Opcode,
+    Operand,
+    Read,
+    Write,
+}
+
+impl Access {
+    fn flag(self) -> u8 {
+        match self {
+            Access::Opcode => OPCODE,
+            Access::Operand => OPERAND,
+            Access::Read => READ,
+            Access::Write => WRITE,
+        }
+    }
+}
+
+/// How a range of bytes was used during a session, as written to a hint file.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum Hint {
+    Code,
+    Data,
+}
+
+/// Per-byte access flags collected while the cpu runs.
+#[derive(Clone, Debug)]
+pub struct Coverage(Vec<u8>);
+
+impl Coverage {
+    pub fn new(len: usize) -> Self {
+        Coverage(vec![0; len])
+    }
+
+    pub fn mark(&mut self, offset: u16, access: Access) {
+        if let Some(flags) = self.0.get_mut(offset as usize) {
+            *flags |= access.flag();
+        }
+    }
+
+    pub fn flags(&self, offset: u16) -> u8 {
+        self.0.get(offset as usize).cloned().unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn count(&self, flag: u8) -> usize {
+        self.0.iter().filter(|f| *f & flag != 0).count()
+    }
+
+    /// Contiguous runs of bytes sharing the same flags, skipping untouched bytes.
+    pub fn ranges(&self) -> Vec<(u16, u16, u8)> {
+        let mut ranges = vec![];
+        let mut start = 0;
+        for i in 1..=self.0.len() {
+            if i == self.0.len() || self.0[i] != self.0[start] {
+                if self.0[start] != 0 {
+                    ranges.push((start as u16, (i - 1) as u16, self.0[start]));
+                }
+                start = i;
+            }
+        }
+        ranges
+    }
+
+    /// Code/data ranges for the disassembler. Anything that was executed is code, anything
+    /// that was only read is data, and bytes that were never touched are left out.
+    pub fn hints(&self) -> Vec<(u16, u16, Hint)> {
+        let mut hints: Vec<(u16, u16, Hint)> = vec![];
+        for (start, end, flags) in self.ranges() {
+            let hint = if flags & (OPCODE | OPERAND) != 0 {
+                Hint::Code
+            } else if flags & READ != 0 {
+                Hint::Data
+            } else {
+                continue;
+            };
+            match hints.last_mut() {
+                Some(last) if last.2 == hint && last.1.wrapping_add(1) == start => last.1 = end,
+                _ => hints.push((start, end, hint)),
+            }
+        }
+        hints
+    }
+
+    pub fn report(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "opcode:  {}", self.count(OPCODE)).unwrap();
+        writeln!(s, "operand: {}", self.count(OPERAND)).unwrap();
+        writeln!(s, "read:    {}", self.count(READ)).unwrap();
+        writeln!(s, "write:   {}", self.count(WRITE)).unwrap();
+        writeln!(s).unwrap();
+        for (start, end, flags) in self.ranges() {
+            writeln!(
+                s,
+                "{:04X}-{:04X} {}{}{}{}",
+                start,
+                end,
+                if flags & OPCODE != 0 { 'x' } else { '-' },
+                if flags & OPERAND != 0 { 'o' } else { '-' },
+                if flags & READ != 0 { 'r' } else { '-' },
+                if flags & WRITE != 0 { 'w' } else { '-' },
+            )
+            .unwrap();
+        }
+        s
+    }
+
+    pub fn hint_file(&self) -> String {
+        let mut s = String::new();
+        for (start, end, hint) in self.hints() {
+            let kind = match hint {
+                Hint::Code => "code",
+                Hint::Data => "data",
+            };
+            writeln!(s, "{} {:04X} {:04X}", kind, start, end).unwrap();
+        }
+        s
+    }
+}
+
+pub fn parse_hints(s: &str) -> Result<Vec<(u16, u16, Hint)>, String> {
+    let mut hints = vec![];
+    for (n, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() != 3 {
+            return Err(format!("bad hint on line {}: {}", n + 1, line));
+        }
+        let hint = match parts[0] {
+            "code" => Hint::Code,
+            "data" => Hint::Data,
+            s => return Err(format!("unknown hint kind on line {}: {}", n + 1, s)),
+        };
+        let start = u16::from_str_radix(parts[1], 16)
+            .map_err(|_| format!("bad start address on line {}", n + 1))?;
+        let end = u16::from_str_radix(parts[2], 16)
+            .map_err(|_| format!("bad end address on line {}", n + 1))?;
+        hints.push((start, end, hint));
+    }
+    Ok(hints)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hints_round_trip() {
+        let mut coverage = Coverage::new(16);
+        coverage.mark(0, Access::Opcode);
+        coverage.mark(1, Access::Operand);
+        coverage.mark(2, Access::Operand);
+        coverage.mark(3, Access::Opcode);
+        coverage.mark(8, Access::Read);
+        coverage.mark(9, Access::Read);
+        coverage.mark(12, Access::Write);
+
+        let hints = coverage.hints();
+        assert_eq!(hints, vec![(0, 3, Hint::Code), (8, 9, Hint::Data)]);
+        assert_eq!(parse_hints(&coverage.hint_file()).unwrap(), hints);
+    }
+
+    #[test]
+    fn test_mark_out_of_range() {
+        let mut coverage = Coverage::new(2);
+        coverage.mark(5, Access::Read);
+        assert_eq!(coverage.flags(5), 0);
+        assert!(coverage.ranges().is_empty());
+    }
+}
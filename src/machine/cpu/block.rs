@@ -0,0 +1,284 @@
+//! An optional execution engine for running faster than `emulate()`. Straight-line runs of
+//! instructions are decoded once and cached by start address; the instructions themselves go
+//! through the same handlers, so only the fetch and decode are skipped. Blocks are dropped
+//! when a write lands on a page they were decoded from.
+
+use crate::machine::cpu::emulate::{emulate, execute};
+use crate::machine::cpu::history::Entry;
+use crate::machine::cpu::table::{self, Exec, OpInfo};
+use crate::machine::cpu::{CPUInterface, Error};
+use crate::machine::memory::Memory;
+use crate::machine::MachineInterface;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Longest run of instructions decoded into one block.
+const MAX_BLOCK: usize = 64;
+
+struct Op {
+    entry: Entry,
+    info: &'static OpInfo,
+}
+
+/// Instructions up to and including the first one that can transfer control.
+struct Block {
+    ops: Vec<Op>,
+}
+
+#[derive(Default)]
+pub struct BlockCache {
+    blocks: HashMap<u16, Arc<Block>>,
+    /// Start addresses of the blocks decoded from each page.
+    by_page: HashMap<usize, Vec<u16>>,
+}
+
+fn ends_block(info: &OpInfo) -> bool {
+    let instruction = (info.decode)(0, 0);
+    instruction.is_jump()
+        || instruction.is_call()
+        || instruction.is_return()
+        || info.mnemonic == "RST"
+        || info.mnemonic == "PCHL"
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Runs cached blocks from the cpu's pc until at least `cycles` cycles have passed,
+    /// stopping on the same instruction `emulate()` would. Anything that can't be decoded
+    /// ahead of time, like HLT or a read past the end of memory, is left to `emulate()`.
+    pub fn run<I: MachineInterface>(
+        &mut self,
+        cpu: &mut CPUInterface,
+        interface: &mut I,
+        cycles: u128,
+    ) -> Result<u128, Error> {
+        let mut ran = 0;
+        while ran < cycles {
+            if cpu.memory.code_written() {
+                self.invalidate(cpu.memory);
+            }
+            let pc = cpu.cpu.pc;
+            let block = match self.blocks.get(&pc) {
+                Some(block) => block.clone(),
+                None => match self.decode(cpu.memory, pc) {
+                    Some(block) => block,
+                    None => {
+                        ran += u128::from(emulate(cpu, interface)?);
+                        continue;
+                    }
+                },
+            };
+            for op in &block.ops {
+                if ran >= cycles || cpu.cpu.pc != op.entry.pc || cpu.memory.code_written() {
+                    break;
+                }
+                ran += u128::from(execute(cpu, interface, op.entry, op.info)?);
+            }
+        }
+        Ok(ran)
+    }
+
+    fn invalidate(&mut self, memory: &mut Memory) {
+        for page in memory.take_written_code() {
+            for start in self.by_page.remove(&page).unwrap_or_default() {
+                self.blocks.remove(&start);
+            }
+        }
+    }
+
+    fn decode(&mut self, memory: &mut Memory, start: u16) -> Option<Arc<Block>> {
+        let mut ops = vec![];
+        let mut pages = vec![];
+        let mut pc = start;
+        'decode: while ops.len() < MAX_BLOCK {
+            let info = match memory.read(pc).ok().and_then(table::op) {
+                Some(info) => info,
+                None => break,
+            };
+            if let Exec::Unimplemented = info.exec {
+                break;
+            }
+            let mut bytes = [0; 3];
+            for i in 0..info.length {
+                bytes[i as usize] = match memory.read(pc.wrapping_add(i.into())) {
+                    Ok(byte) => byte,
+                    Err(_) => break 'decode,
+                };
+            }
+            for i in 0..info.length {
+                let page = memory.watch_code(pc.wrapping_add(i.into()));
+                if !pages.contains(&page) {
+                    pages.push(page);
+                }
+            }
+            ops.push(Op {
+                entry: Entry {
+                    pc,
+                    bytes,
+                    length: info.length,
+                },
+                info,
+            });
+            pc = pc.wrapping_add(info.length.into());
+            if ends_block(info) {
+                break;
+            }
+        }
+
+        if ops.is_empty() {
+            return None;
+        }
+        for page in pages {
+            self.by_page.entry(page).or_default().push(start);
+        }
+        let block = Arc::new(Block { ops });
+        self.blocks.insert(start, block.clone());
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::assembler::assemble;
+    use crate::machine::cpu::testing::{self, TestInterface};
+    use crate::machine::cpu::{self, CPU};
+    use crate::machine::memory_map::{MemoryMap, RegionKind};
+    use rand::Rng;
+
+    const MEMORY_SIZE: usize = 0x4000;
+
+    /// Runs `cpu` over `mem` for `cycles` cycles, through the block cache or one
+    /// instruction at a time.
+    fn run(mut cpu: CPU, mem: Vec<u8>, cycles: u128, cached: bool) -> (CPU, Memory, bool) {
+        let mut memory = testing::memory(&mem);
+        let mut interface = TestInterface { input: 0x5a };
+        let ok = {
+            let mut state = CPUInterface {
+                cpu: &mut cpu,
+                memory: &mut memory,
+            };
+            if cached {
+                BlockCache::new()
+                    .run(&mut state, &mut interface, cycles)
+                    .is_ok()
+            } else {
+                let mut ran = 0;
+                let mut result = Ok(());
+                while ran < cycles && result.is_ok() {
+                    result = emulate(&mut state, &mut interface).map(|c| ran += u128::from(c));
+                }
+                result.is_ok()
+            }
+        };
+        (cpu, memory, ok)
+    }
+
+    fn assert_same(a: &(CPU, Memory, bool), b: &(CPU, Memory, bool)) {
+        assert_eq!(a.2, b.2, "one engine failed");
+        assert_eq!(format!("{}", a.0), format!("{}", b.0));
+        assert_eq!(a.0.int_enable, b.0.int_enable);
+        for adr in 0..MEMORY_SIZE as u16 {
+            assert_eq!(
+                a.1.read(adr).unwrap(),
+                b.1.read(adr).unwrap(),
+                "at {:#06X}",
+                adr
+            );
+        }
+    }
+
+    /// Random code writes all over itself, which exercises invalidation as much as decoding.
+    #[test]
+    fn test_matches_emulate() {
+        let mut rng = testing::rng();
+        for _ in 0..200 {
+            let mut mem = vec![0; MEMORY_SIZE];
+            rng.fill_bytes(&mut mem);
+            let regs: [u8; 7] = rng.gen();
+            let (sp, pc) = (rng.gen(), rng.gen_range(0, MEMORY_SIZE as u16));
+            let start = || {
+                let mut cpu = cpu::new();
+                cpu.a = regs[0];
+                cpu.b = regs[1];
+                cpu.c = regs[2];
+                cpu.d = regs[3];
+                cpu.e = regs[4];
+                cpu.h = regs[5];
+                cpu.l = regs[6];
+                cpu.sp = sp;
+                cpu.pc = pc;
+                cpu
+            };
+            let cycles = rng.gen_range(1u32, 5000).into();
+            assert_same(
+                &run(start(), mem.clone(), cycles, false),
+                &run(start(), mem, cycles, true),
+            );
+        }
+    }
+
+    #[test]
+    fn test_self_modifying() {
+        // Turns the INR at the top of its own loop into a DCR on the second pass, after the
+        // loop has been cached.
+        let program = assemble(
+            "
+        MVI  B,3
+LOOP:   INR  C
+        MOV  A,B
+        CPI  2
+        JNZ  SKIP
+        MVI  A,$0D
+        STA  LOOP
+SKIP:   DCR  B
+        JNZ  LOOP
+        JMP  $
+",
+        )
+        .unwrap();
+        let mut mem = vec![0; MEMORY_SIZE];
+        mem[..program.bytes.len()].copy_from_slice(&program.bytes);
+        let cached = run(cpu::new(), mem.clone(), 500, true);
+        assert_eq!(cached.0.c, 1);
+        assert_same(&run(cpu::new(), mem, 500, false), &cached);
+    }
+
+    #[test]
+    fn test_bank_switch() {
+        let map = MemoryMap::new()
+            .region(0x0000, 0x00ff, RegionKind::Ram)
+            .region(0x0100, 0x01ff, RegionKind::Banked(2));
+        let mut memory = Memory::with_map(&map).unwrap();
+        memory.load(0x0000, &assemble("JMP $0100").unwrap().bytes);
+        memory.load(0x0100, &assemble("INR B\nJMP $0000").unwrap().bytes);
+        memory.select_bank(0x0100, 1).unwrap();
+        memory.load(0x0100, &assemble("DCR B\nJMP $0000").unwrap().bytes);
+        memory.select_bank(0x0100, 0).unwrap();
+
+        let mut cpu = cpu::new();
+        let mut blocks = BlockCache::new();
+        let mut interface = TestInterface { input: 0 };
+        let mut run = |cpu: &mut CPU, memory: &mut Memory| {
+            let mut state = CPUInterface { cpu, memory };
+            blocks.run(&mut state, &mut interface, 100).unwrap();
+        };
+        run(&mut cpu, &mut memory);
+        assert!(cpu.b > 0);
+        memory.select_bank(0x0100, 1).unwrap();
+        let b = cpu.b;
+        run(&mut cpu, &mut memory);
+        assert!(cpu.b < b);
+    }
+}
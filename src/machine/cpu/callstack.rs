@@ -0,0 +1,161 @@
+use std::fmt;
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+/// One entry on the shadow call stack. `sp` is the stack pointer after the return address was
+/// pushed, which is where the matching RET will find it.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub call_site: u16,
+    pub target: u16,
+    pub sp: u16,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum Imbalance {
+    /// A RET popped an address no CALL/RST/interrupt had pushed.
+    Unmatched { pc: u16, sp: u16 },
+    /// A RET skipped over frames, e.g. after the program moved SP or popped a return address.
+    Discarded { pc: u16, frames: Vec<Frame> },
+}
+
+impl fmt::Display for Imbalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            Imbalance::Unmatched { pc, sp } => write!(
+                f,
+                "RET at {:#06X} without matching CALL (sp {:#06X})",
+                pc, sp
+            ),
+            Imbalance::Discarded { pc, frames } => write!(
+                f,
+                "RET at {:#06X} discarded {} unreturned frame(s)",
+                pc,
+                frames.len()
+            ),
+        }
+    }
+}
+
+/// Shadow of the 8080 stack that only tracks return addresses, so errors can report where the
+/// cpu was called from.
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    /// RETs that didn't match a frame, counted rather than reported as they happen since some
+    /// programs do it all the time.
+    imbalances: usize,
+    last_imbalance: Option<Imbalance>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        CallStack::default()
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// How many RETs so far haven't matched a frame, and the latest of them.
+    pub fn imbalances(&self) -> (usize, Option<&Imbalance>) {
+        (self.imbalances, self.last_imbalance.as_ref())
+    }
+
+    pub fn push(&mut self, kind: FrameKind, call_site: u16, target: u16, sp: u16) {
+        self.frames.push(Frame {
+            kind,
+            call_site,
+            target,
+            sp,
+        });
+    }
+
+    /// Pops the frame whose return address lives at `sp`. Frames pushed below `sp` can no
+    /// longer be returned to and are dropped along the way. An imbalance is also counted.
+    pub fn ret(&mut self, pc: u16, sp: u16) -> Result<Frame, Imbalance> {
+        let result = self.pop(pc, sp);
+        if let Err(imbalance) = &result {
+            self.imbalances += 1;
+            self.last_imbalance = Some(imbalance.clone());
+        }
+        result
+    }
+
+    fn pop(&mut self, pc: u16, sp: u16) -> Result<Frame, Imbalance> {
+        let mut discarded = vec![];
+        while let Some(top) = self.frames.last().cloned() {
+            if top.sp >= sp {
+                break;
+            }
+            discarded.push(top);
+            self.frames.pop();
+        }
+
+        match self.frames.last().cloned() {
+            Some(top) if top.sp == sp => {
+                self.frames.pop();
+                if discarded.is_empty() {
+                    Ok(top)
+                } else {
+                    Err(Imbalance::Discarded {
+                        pc,
+                        frames: discarded,
+                    })
+                }
+            }
+            _ => Err(Imbalance::Unmatched { pc, sp }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balanced() {
+        let mut stack = CallStack::new();
+        stack.push(FrameKind::Call, 0x10, 0x100, 0x23FE);
+        stack.push(FrameKind::Interrupt, 0x104, 0x08, 0x23FC);
+        assert_eq!(stack.ret(0x09, 0x23FC).unwrap().target, 0x08);
+        assert_eq!(stack.ret(0x105, 0x23FE).unwrap().call_site, 0x10);
+        assert_eq!(stack.depth(), 0);
+        assert_eq!(stack.imbalances(), (0, None));
+    }
+
+    #[test]
+    fn test_unmatched_ret() {
+        let mut stack = CallStack::new();
+        assert_eq!(
+            stack.ret(0x20, 0x2400),
+            Err(Imbalance::Unmatched {
+                pc: 0x20,
+                sp: 0x2400
+            })
+        );
+        assert_eq!(stack.imbalances().0, 1);
+    }
+
+    #[test]
+    fn test_discarded_frames() {
+        let mut stack = CallStack::new();
+        stack.push(FrameKind::Call, 0x10, 0x100, 0x23FE);
+        stack.push(FrameKind::Call, 0x104, 0x200, 0x23FC);
+        match stack.ret(0x105, 0x23FE) {
+            Err(Imbalance::Discarded { frames, .. }) => assert_eq!(frames[0].target, 0x200),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(stack.depth(), 0);
+    }
+}
@@ -17,6 +17,12 @@ impl Fail for Error {
     }
 }
 
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        self.inner.get_context()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Display::fmt(&self.inner, f)
@@ -55,9 +61,6 @@ impl From<ErrorKind> for Error {
 
 #[derive(Fail, Debug)]
 pub enum ErrorKind {
-    #[fail(display = "Could not obtain CPU Lock")]
-    LockErr,
-
     #[fail(display = "MemoryError {}", _0)]
     MemoryError(#[fail(cause)] crate::machine::memory::Error),
 
@@ -70,18 +73,22 @@ pub enum ErrorKind {
     #[fail(display = "Unknown Op: {}", _0)]
     UnknownOp(u8),
 
-    #[fail(display = "Unimplemented Instruction: {:?}", _0)]
-    UnimplementedInstruction(OpCode),
-
     #[fail(display = "Unimplemented Op: {:?}", _0)]
     UnimplementedOp(OpCode),
 
     #[fail(display = " OpError: {}", _0)]
     OpError(String),
 
+    #[fail(display = "TraceError {}", _0)]
+    TraceError(#[fail(cause)] std::io::Error),
+
     #[fail(display = "exit: {}", _0)]
     Exit(u8),
 
+    /// The history, and it formatted with the cpu's symbols.
+    #[fail(display = "{}", _1)]
+    History(crate::machine::cpu::History, String),
+
     #[fail(display = "{}", _0)]
-    History(crate::machine::cpu::History),
+    Backtrace(String),
 }
@@ -0,0 +1,205 @@
+use crate::machine::cpu::table;
+use crate::machine::symbols::Symbols;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// Number of instructions kept unless the machine asks for a different depth.
+pub const DEFAULT_DEPTH: usize = 256;
+
+/// One executed instruction: where it was fetched from and the bytes it was decoded from.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Entry {
+    pub pc: u16,
+    pub bytes: [u8; 3],
+    pub length: u8,
+}
+
+impl Entry {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length as usize]
+    }
+
+    /// The address, bytes and instruction, with the operand labelled from `symbols` and the
+    /// address described after a `;` when it falls in a known routine.
+    pub fn format(&self, symbols: &Symbols) -> String {
+        let bytes: Vec<String> = self.bytes().iter().map(|b| format!("{:02X}", b)).collect();
+        let mut s = format!("{:#06X}  {:<8}", self.pc, bytes.join(" "));
+        if let Some(info) = table::op(self.bytes[0]) {
+            let instruction = (info.decode)(self.bytes[1], self.bytes[2]);
+            s.push_str(&format!("  {:<16}", symbols.format(&instruction)));
+        }
+        let place = symbols.describe(self.pc);
+        if !place.starts_with('$') {
+            s.push_str(&format!("  ; {}", place));
+        }
+        s.trim_end().to_owned()
+    }
+}
+
+/// The most recently executed instructions, oldest first. Recording overwrites a slot in place;
+/// the ring is only cloned when an error captures it.
+#[derive(Clone, Debug)]
+pub struct History {
+    entries: Vec<Entry>,
+    next: usize,
+    depth: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_DEPTH)
+    }
+}
+
+impl History {
+    /// A history keeping the last `depth` instructions. A depth of 0 records nothing.
+    pub fn new(depth: usize) -> Self {
+        History {
+            entries: Vec::with_capacity(depth),
+            next: 0,
+            depth,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Changes how many instructions are kept, dropping the oldest ones that no longer fit.
+    pub fn set_depth(&mut self, depth: usize) {
+        let keep: Vec<Entry> = self.iter().cloned().collect();
+        *self = History::new(depth);
+        for entry in keep {
+            self.push(entry);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn push(&mut self, entry: Entry) {
+        if self.entries.len() < self.depth {
+            self.entries.push(entry);
+        } else if self.depth > 0 {
+            self.entries[self.next] = entry;
+            self.next = (self.next + 1) % self.depth;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries[self.next..]
+            .iter()
+            .chain(self.entries[..self.next].iter())
+    }
+
+    /// Every entry, oldest first, a line each.
+    pub fn format(&self, symbols: &Symbols) -> String {
+        self.iter()
+            .map(|entry| entry.format(symbols) + "\n")
+            .collect()
+    }
+}
+
+/// Every executed instruction written out as it runs, one `Entry` per line.
+pub struct Trace {
+    out: BufWriter<File>,
+}
+
+impl Trace {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Trace {
+            out: BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, entry: &Entry, symbols: &Symbols) -> io::Result<()> {
+        writeln!(self.out, "{}", entry.format(symbols))
+    }
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(f, "Trace")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::cpu::{testing, Error, ErrorKind};
+    use failure::Fail;
+
+    fn entry(pc: u16) -> Entry {
+        Entry {
+            pc,
+            bytes: [0, 0, 0],
+            length: 1,
+        }
+    }
+
+    fn pcs(history: &History) -> Vec<u16> {
+        history.iter().map(|e| e.pc).collect()
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut history = History::new(3);
+        for pc in 0..5 {
+            history.push(entry(pc));
+        }
+        assert_eq!(pcs(&history), vec![2, 3, 4]);
+
+        history.set_depth(2);
+        assert_eq!(pcs(&history), vec![3, 4]);
+        history.set_depth(4);
+        history.push(entry(5));
+        assert_eq!(pcs(&history), vec![3, 4, 5]);
+
+        let mut off = History::new(0);
+        off.push(entry(0));
+        assert!(off.is_empty());
+    }
+
+    #[test]
+    fn test_display() {
+        let jmp = Entry {
+            pc: 0x1A5C,
+            bytes: [0xc3, 0x00, 0x18],
+            length: 3,
+        };
+        assert_eq!(jmp.bytes(), &[0xc3, 0x00, 0x18]);
+        assert_eq!(jmp.format(&Symbols::new()), "0x1A5C  C3 00 18  JMP $1800");
+        let symbols = Symbols::parse("1800 Start\n1A50 Loop").unwrap();
+        assert_eq!(
+            jmp.format(&symbols),
+            "0x1A5C  C3 00 18  JMP Start         ; Loop+0xC"
+        );
+    }
+
+    #[test]
+    fn test_captured_on_error() {
+        // MVI A,$01; INR A; HLT
+        let err = match testing::run_bytes(0, &[0x3e, 0x01, 0x3c, 0x76], "") {
+            Err(e) => e,
+            Ok(_) => panic!("HLT should be unimplemented"),
+        };
+        let mut cause: Option<&dyn Fail> = Some(&err);
+        while let Some(c) = cause {
+            if let Some(ErrorKind::History(history, _)) = c.downcast_ref::<Error>().map(Error::kind)
+            {
+                assert_eq!(pcs(history), vec![0, 2, 3]);
+                assert_eq!(history.iter().next().unwrap().bytes(), &[0x3e, 0x01]);
+                return;
+            }
+            cause = c.cause();
+        }
+        panic!("no history in {}", err);
+    }
+}
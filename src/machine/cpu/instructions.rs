@@ -1,9 +1,10 @@
+use crate::machine::cpu::callstack::FrameKind;
 use crate::machine::cpu::ops::Register;
 use crate::machine::cpu::ops::Register::*;
 use crate::machine::cpu::CPUInterface;
 use crate::machine::cpu::{Error, ErrorKind};
 
-type OpResult = Result<u8, Error>;
+pub(crate) type OpResult = Result<u8, Error>;
 
 /// ADD
 pub(crate) fn add(reg: Register, state: &mut CPUInterface) -> OpResult {
@@ -319,7 +320,7 @@ pub(crate) fn lhld(state: &mut CPUInterface) -> OpResult {
     let h = state.read_1()?;
     let adr = to_adr(h, l);
     state.cpu.l = state.read(adr)?;
-    state.cpu.h = state.read(adr + 1)?;
+    state.cpu.h = state.read(adr.wrapping_add(1))?;
     Ok(16)
 }
 
@@ -472,11 +473,13 @@ pub(crate) fn push(reg: Register, state: &mut CPUInterface) -> OpResult {
         }
         PSW => {
             let a = state.cpu.a;
-            let psw = int_bool(state.cpu.cc.z)
-                | int_bool(state.cpu.cc.s) << 1
+            // S Z 0 AC 0 P 1 CY
+            let psw = int_bool(state.cpu.cc.s) << 7
+                | int_bool(state.cpu.cc.z) << 6
+                | int_bool(state.cpu.cc.ac) << 4
                 | int_bool(state.cpu.cc.p) << 2
-                | int_bool(state.cpu.cc.cy) << 3
-                | int_bool(state.cpu.cc.ac) << 4;
+                | 0x02
+                | int_bool(state.cpu.cc.cy);
             state.write(sp.wrapping_sub(1), a)?;
             state.write(sp.wrapping_sub(2), psw)?;
         }
@@ -530,11 +533,11 @@ pub(crate) fn pop(reg: Register, state: &mut CPUInterface) -> OpResult {
             let sp = state.cpu.sp;
             state.cpu.a = state.read(sp.wrapping_add(1))?;
             let psw = state.read(sp)?;
-            state.cpu.cc.z = 0x01 == (psw & 0x01);
-            state.cpu.cc.s = 0x02 == (psw & 0x02);
-            state.cpu.cc.p = 0x04 == (psw & 0x04);
-            state.cpu.cc.cy = 0x08 == (psw & 0x08);
+            state.cpu.cc.s = 0x80 == (psw & 0x80);
+            state.cpu.cc.z = 0x40 == (psw & 0x40);
             state.cpu.cc.ac = 0x10 == (psw & 0x10);
+            state.cpu.cc.p = 0x04 == (psw & 0x04);
+            state.cpu.cc.cy = 0x01 == (psw & 0x01);
         }
         _ => unimplemented!("unimplemented inx: {:?}", reg),
     };
@@ -542,19 +545,18 @@ pub(crate) fn pop(reg: Register, state: &mut CPUInterface) -> OpResult {
     Ok(10)
 }
 
-pub(crate) fn log<F: Fn(u8, u8) -> u8>(
-    reg: Register,
-    state: &mut CPUInterface,
-    cycles: u8,
-    op: F,
-) -> OpResult {
+pub(crate) fn log<F: Fn(u8, u8) -> u8>(reg: Register, state: &mut CPUInterface, op: F) -> OpResult {
     state.advance()?;
+    let mut cycles = 4;
     let answer = match &reg {
         SP | PSW => {
             unimplemented!("unimplemented tmp: {:?}", reg);
         }
 
-        M => op(state.cpu.a, read_hl(state)?),
+        M => {
+            cycles = 7;
+            op(state.cpu.a, read_hl(state)?)
+        }
 
         r => op(state.cpu.a, state.get_u8(*r)),
     };
@@ -606,9 +608,12 @@ pub(crate) fn cmp(reg: Register, state: &mut CPUInterface) -> OpResult {
 }
 
 pub(crate) fn ret_if<F: Fn(&CPUInterface) -> bool>(state: &mut CPUInterface, cond: F) -> OpResult {
+    let pc = state.cpu.pc;
     state.advance()?;
     if cond(state) {
         let sp = state.cpu.sp;
+        // Imbalances are counted on the call stack and shown with the next backtrace.
+        let _ = state.cpu.call_stack.ret(pc, sp);
         let l = state.read(sp)?;
         let h = state.read(sp.wrapping_add(1))?;
         state.cpu.pc = to_adr(h, l);
@@ -619,6 +624,11 @@ pub(crate) fn ret_if<F: Fn(&CPUInterface) -> bool>(state: &mut CPUInterface, con
     }
 }
 
+/// Unconditional RET, which takes a cycle less than a conditional one that is taken.
+pub(crate) fn ret(state: &mut CPUInterface) -> OpResult {
+    ret_if(state, |_| true).map(|_| 10)
+}
+
 pub(crate) fn jmp_if<F: Fn(&CPUInterface) -> bool>(state: &mut CPUInterface, f: F) -> OpResult {
     state.advance()?;
     if f(state) {
@@ -662,9 +672,12 @@ pub(crate) fn call(state: &mut CPUInterface) -> OpResult {
                 offset += 1;
             }
             print!("{}", buf);
+            state.cpu.output.push_str(&buf);
         } else if state.cpu.c == 2 {
             //saw this in the inspected code, never saw it called
-            print!("{:#X?}", state.cpu.e.to_ascii_uppercase());
+            let c = format!("{:#X?}", state.cpu.e.to_ascii_uppercase());
+            print!("{}", c);
+            state.cpu.output.push_str(&c);
         }
         Ok(17)
     } else if state.cpu.debug && 0 == (u16::from(h) << 8) | u16::from(l) {
@@ -679,11 +692,33 @@ pub(crate) fn call(state: &mut CPUInterface) -> OpResult {
         state.write(sp.wrapping_sub(1), ((ret >> 8) & 0xff) as u8)?;
         state.write(sp2, (ret & 0xff) as u8)?;
         state.cpu.sp = sp2 as u16;
+        state
+            .cpu
+            .call_stack
+            .push(FrameKind::Call, ret.wrapping_sub(3), state.cpu.pc, sp2);
 
         Ok(17)
     }
 }
 
+pub(crate) fn rst(n: u16, state: &mut CPUInterface) -> OpResult {
+    let call_site = state.cpu.pc;
+    state.advance()?;
+    let ret = state.cpu.pc;
+    let sp = state.cpu.sp;
+    let sp2 = sp.wrapping_sub(2);
+
+    state.write(sp.wrapping_sub(1), ((ret >> 8) & 0xff) as u8)?;
+    state.write(sp2, (ret & 0xff) as u8)?;
+    state.cpu.sp = sp2;
+    state.cpu.pc = n * 8;
+    state
+        .cpu
+        .call_stack
+        .push(FrameKind::Rst, call_site, state.cpu.pc, sp2);
+    Ok(11)
+}
+
 fn to_adr(h: u8, l: u8) -> u16 {
     ((u16::from(h)) << 8 | u16::from(l))
 }
@@ -742,6 +777,7 @@ pub(crate) fn rar(state: &mut CPUInterface) -> OpResult {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::machine::cpu::testing::{check, check_bytes};
     use crate::machine::cpu::*;
     use crate::machine::memory::Memory;
     use std::sync::RwLock;
@@ -774,4 +810,333 @@ mod test {
         assert_eq!(interface.cpu.cc.cy, true);
     }
 
+    #[test]
+    fn test_data_transfer() {
+        check("MOV B,C", "c=$42", "b=$42 pc=1", 5);
+        check("MOV M,A", "a=$42 hl=$2400", "[$2400]=$42", 7);
+        check("MOV A,M", "hl=$2400 [$2400]=$42", "a=$42", 7);
+        check("MVI D,$99", "", "d=$99 pc=2", 7);
+        check("MVI M,$99", "hl=$2400", "[$2400]=$99", 10);
+        check("LXI H,$1234", "", "hl=$1234 pc=3", 10);
+        check("LXI SP,$2400", "", "sp=$2400", 10);
+        check("LDA $2400", "[$2400]=$42", "a=$42", 13);
+        check("STA $2400", "a=$42", "[$2400]=$42", 13);
+        check("LHLD $2400", "[$2400]=$34 [$2401]=$12", "hl=$1234", 16);
+        check("SHLD $2400", "hl=$1234", "[$2400]=$34 [$2401]=$12", 16);
+        check("LDAX B", "bc=$2400 [$2400]=$42", "a=$42", 7);
+        check("STAX D", "a=$42 de=$2400", "[$2400]=$42", 7);
+        check("XCHG", "hl=$1234 de=$5678", "hl=$5678 de=$1234", 4);
+        check(
+            "XTHL",
+            "sp=$2400 hl=$1234 [$2400]=$78 [$2401]=$56",
+            "hl=$5678 [$2400]=$34 [$2401]=$12 sp=$2400",
+            18,
+        );
+        check("SPHL", "hl=$1234", "sp=$1234", 5);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        check("ADD B", "a=$6C b=$2E", "a=$9A s !z p !cy", 4);
+        check("ADD M", "a=$80 hl=$2400 [$2400]=$80", "a=0 z !s p cy", 7);
+        check("ADI $42", "a=$14", "a=$56 !z !cy p", 7);
+        check("ACI $01", "a=$FF cy", "a=$01 cy !z", 7);
+        check("ADC C", "a=$42 c=$3D cy", "a=$80 s !cy", 4);
+        check("SUB B", "a=$3E b=$3E", "a=0 z !cy p", 4);
+        check("SUB B", "a=$10 b=$20", "a=$F0 cy s", 4);
+        check("SUI $01", "a=0", "a=$FF cy s p", 7);
+        check("SBB L", "a=$04 l=$02 cy", "a=$01 !cy !z", 4);
+        check("SBI $01", "a=0 cy", "a=$FE cy s", 7);
+        check("INR A", "a=$FF cy", "a=0 z cy", 5);
+        check("INR M", "hl=$2400 [$2400]=$7F", "[$2400]=$80 s", 10);
+        check("DCR B", "b=$01", "b=0 z", 5);
+        check("DCR M", "hl=$2400 [$2400]=0", "[$2400]=$FF s", 10);
+        check("INX D", "de=$00FF", "de=$0100", 5);
+        check("INX SP", "sp=$FFFF", "sp=0", 5);
+        check("DCX H", "hl=0", "hl=$FFFF", 5);
+        check("DAD B", "hl=$A17B bc=$339F", "hl=$D51A !cy", 10);
+        check("DAD SP", "hl=$8000 sp=$8000", "hl=0 cy", 10);
+        check("CMP E", "a=$0A e=$05", "a=$0A !z !cy", 4);
+        check("CMP E", "a=$02 e=$05", "!z cy s", 4);
+        check("CPI $40", "a=$40", "z !cy", 7);
+    }
+
+    #[test]
+    fn test_logical() {
+        check("ANA C", "a=$FC c=$0F cy", "a=$0C !cy", 4);
+        check("XRA A", "a=$5A cy", "a=0 z p !cy", 4);
+        check("ORA M", "a=$33 hl=$2400 [$2400]=$0F", "a=$3F !z p", 7);
+        check("ANI $0F", "a=$3A", "a=$0A", 7);
+        check("ORI $0F", "a=$B5", "a=$BF s", 7);
+        check("XRI $FF", "a=$0F", "a=$F0 s", 7);
+        check("CMA", "a=$51", "a=$AE", 4);
+        check("RLC", "a=$F2", "a=$E5 cy", 4);
+        check("RRC", "a=$F2", "a=$79 !cy", 4);
+        check("RAL", "a=$B5 !cy", "a=$6A cy", 4);
+        check("RAR", "a=$6A cy", "a=$B5 !cy", 4);
+        check("STC", "", "cy", 4);
+        check("CMC", "cy", "!cy", 4);
+    }
+
+    #[test]
+    fn test_branch() {
+        check("JMP $1234", "", "pc=$1234", 10);
+        check("JNZ $1234", "!z", "pc=$1234", 10);
+        check("JNZ $1234", "z", "pc=3", 10);
+        check("JC $1234", "cy", "pc=$1234", 10);
+        check("JPE $1234", "!p", "pc=3", 10);
+        check("JM $1234", "s", "pc=$1234", 10);
+        check(
+            "CALL $1234",
+            "sp=$2400",
+            "pc=$1234 sp=$23FE [$23FE]=3 [$23FF]=0",
+            17,
+        );
+        check("CNZ $1234", "z sp=$2400", "pc=3 sp=$2400", 11);
+        check("CC $1234", "cy sp=$2400", "pc=$1234 sp=$23FE", 17);
+        check(
+            "RET",
+            "sp=$23FE [$23FE]=$34 [$23FF]=$12",
+            "pc=$1234 sp=$2400",
+            10,
+        );
+        check("RZ", "!z sp=$23FE", "pc=1 sp=$23FE", 5);
+        check("RZ", "z sp=$23FE [$23FE]=$34 [$23FF]=$12", "pc=$1234", 11);
+        check("PCHL", "hl=$1234", "pc=$1234", 5);
+        check("RST 1", "sp=$2400", "pc=8 sp=$23FE [$23FE]=1", 11);
+    }
+
+    #[test]
+    fn test_stack() {
+        check(
+            "PUSH B",
+            "bc=$1234 sp=$2400",
+            "sp=$23FE [$23FF]=$12 [$23FE]=$34",
+            11,
+        );
+        check(
+            "POP H",
+            "sp=$23FE [$23FE]=$34 [$23FF]=$12",
+            "hl=$1234 sp=$2400",
+            10,
+        );
+        check(
+            "PUSH PSW\n MVI A,0\n STC\n POP PSW",
+            "a=$42 z !cy sp=$2400",
+            "a=$42 z !cy sp=$2400 [$23FF]=$42",
+            11 + 7 + 4 + 10,
+        );
+    }
+
+    #[test]
+    fn test_io_and_control() {
+        check("IN $01", "in=$42", "a=$42 pc=2", 10);
+        check("OUT $03", "a=$42", "a=$42 pc=2", 10);
+        check("EI", "", "ie", 4);
+        check("DI", "ie", "!ie", 4);
+        check("NOP", "", "pc=1", 4);
+        check_bytes(&[0x08], "", "pc=1", 4);
+    }
+
+    const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "M", "A"];
+
+    /// Sets `reg` to `value`, through HL=$2400 for M.
+    fn set(reg: &str, value: u8) -> String {
+        match reg {
+            "M" => format!("hl=$2400 [$2400]={}", value),
+            r => format!("{}={}", r.to_lowercase(), value),
+        }
+    }
+
+    #[test]
+    fn test_every_register() {
+        // H and L double as the address of M, so they get its high and low byte.
+        let values = [1, 2, 3, 4, 0x24, 0x05, 8, 7];
+        let given = "b=1 c=2 d=3 e=4 h=$24 l=5 a=7 [$2405]=8";
+        for (d, dst) in REGISTERS.iter().enumerate() {
+            for (s, src) in REGISTERS.iter().enumerate() {
+                if *dst == "M" && *src == "M" {
+                    // HLT
+                    continue;
+                }
+                let expected = match *dst {
+                    "M" => format!("[$2405]={}", values[s]),
+                    r => format!("{}={}", r.to_lowercase(), values[s]),
+                };
+                let cycles = if d == 6 || s == 6 { 7 } else { 5 };
+                check(&format!("MOV {},{}", dst, src), given, &expected, cycles);
+            }
+        }
+
+        for reg in REGISTERS.iter() {
+            let (mvi, inr) = if *reg == "M" { (10, 10) } else { (7, 5) };
+            check(
+                &format!("MVI {},$99", reg),
+                &set(reg, 0),
+                &set(reg, 0x99),
+                mvi,
+            );
+            check(
+                &format!("INR {}", reg),
+                &set(reg, 0x10),
+                &set(reg, 0x11),
+                inr,
+            );
+            check(
+                &format!("DCR {}", reg),
+                &set(reg, 0x10),
+                &set(reg, 0x0F),
+                inr,
+            );
+        }
+
+        // A=$35 against $0F, with carry in.
+        let ops: [(&str, u8, u8); 8] = [
+            ("ADD", 0x44, 0x6A),
+            ("ADC", 0x45, 0x6B),
+            ("SUB", 0x26, 0x00),
+            ("SBB", 0x25, 0xFF),
+            ("ANA", 0x05, 0x35),
+            ("XRA", 0x3A, 0x00),
+            ("ORA", 0x3F, 0x35),
+            ("CMP", 0x35, 0x35),
+        ];
+        for &(op, result, with_a) in ops.iter() {
+            for reg in REGISTERS.iter() {
+                let (given, result) = match *reg {
+                    "A" => ("a=$35 cy".to_owned(), with_a),
+                    r => (format!("a=$35 {} cy", set(r, 0x0F)), result),
+                };
+                let cycles = if *reg == "M" { 7 } else { 4 };
+                check(
+                    &format!("{} {}", op, reg),
+                    &given,
+                    &format!("a={}", result),
+                    cycles,
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_pair() {
+        for (pair, name) in [("B", "bc"), ("D", "de"), ("H", "hl"), ("SP", "sp")].iter() {
+            check(
+                &format!("INX {}", pair),
+                &format!("{}=$12FF", name),
+                &format!("{}=$1300", name),
+                5,
+            );
+            check(
+                &format!("DCX {}", pair),
+                &format!("{}=$1300", name),
+                &format!("{}=$12FF", name),
+                5,
+            );
+            let (given, expected) = match *name {
+                "hl" => ("hl=$1234".to_owned(), "hl=$2468"),
+                _ => (format!("hl=$1000 {}=$1234", name), "hl=$2234"),
+            };
+            check(&format!("DAD {}", pair), &given, expected, 10);
+        }
+        for (pair, name) in [("B", "bc"), ("D", "de"), ("H", "hl")].iter() {
+            check(
+                &format!("PUSH {}\n LXI {},0\n POP {}", pair, pair, pair),
+                &format!("{}=$1234 sp=$2400", name),
+                &format!("{}=$1234 sp=$2400 [$23FF]=$12 [$23FE]=$34", name),
+                11 + 10 + 10,
+            );
+            check(
+                &format!("LXI {},$1234", pair),
+                "",
+                &format!("{}=$1234", name),
+                10,
+            );
+        }
+        check("LDAX D", "de=$2400 [$2400]=$42", "a=$42", 7);
+        check("STAX B", "a=$42 bc=$2400", "[$2400]=$42", 7);
+    }
+
+    #[test]
+    fn test_every_condition() {
+        // Each condition with the flag that makes it true, then false.
+        let conditions = [
+            ("NZ", "!z", "z"),
+            ("Z", "z", "!z"),
+            ("NC", "!cy", "cy"),
+            ("C", "cy", "!cy"),
+            ("PO", "!p", "p"),
+            ("PE", "p", "!p"),
+            ("P", "!s", "s"),
+            ("M", "s", "!s"),
+        ];
+        let stacked = "sp=$23FE [$23FE]=$34 [$23FF]=$12";
+        for &(cond, taken, not_taken) in conditions.iter() {
+            let jump = format!("J{} $1234", cond);
+            check(&jump, taken, "pc=$1234", 10);
+            check(&jump, not_taken, "pc=3", 10);
+
+            let call = format!("C{} $1234", cond);
+            check(
+                &call,
+                &format!("{} sp=$2400", taken),
+                "pc=$1234 sp=$23FE [$23FE]=3",
+                17,
+            );
+            check(
+                &call,
+                &format!("{} sp=$2400", not_taken),
+                "pc=3 sp=$2400",
+                11,
+            );
+
+            let ret = format!("R{}", cond);
+            check(
+                &ret,
+                &format!("{} {}", taken, stacked),
+                "pc=$1234 sp=$2400",
+                11,
+            );
+            check(
+                &ret,
+                &format!("{} {}", not_taken, stacked),
+                "pc=1 sp=$23FE",
+                5,
+            );
+        }
+        // Away from 0, so that RST 0 doesn't land back on the program.
+        for n in 0..8 {
+            check(
+                &format!("ORG $100\n RST {}", n),
+                "sp=$2400",
+                &format!("pc={} sp=$23FE [$23FE]=1 [$23FF]=1", n * 8),
+                11,
+            );
+        }
+    }
+
+    #[test]
+    fn test_undocumented_nops() {
+        // Including RIM, which the 8080 doesn't have.
+        let codes = [
+            0x08, 0x10, 0x18, 0x20, 0x28, 0x38, 0xcb, 0xd9, 0xdd, 0xed, 0xfd,
+        ];
+        for &code in codes.iter() {
+            check_bytes(&[code], "a=$42", "a=$42 pc=1", 4);
+        }
+        // Only the pc moves until the auxiliary carry is tracked.
+        check("DAA", "a=$9B", "a=$9B pc=1", 4);
+    }
+
+    #[test]
+    fn test_program() {
+        check(
+            "        MVI B,3
+loop:   DCR B
+        JNZ loop",
+            "",
+            "b=0 z pc=6",
+            7 + 3 * (5 + 10),
+        );
+    }
 }
@@ -1,21 +1,32 @@
+pub mod block;
+pub mod callstack;
 pub mod disassembler;
+pub mod history;
 
 use crate::machine::cpu::ops::*;
 pub mod ops;
+pub mod syntax;
+pub mod table;
+use crate::machine::coverage::Access;
+use crate::machine::cpu::callstack::{CallStack, FrameKind};
 use crate::machine::memory::Memory;
+use crate::machine::symbols::Symbols;
 
 mod emulate;
 mod error;
 pub mod instructions;
+#[cfg(test)]
+mod reference;
+#[cfg(test)]
+pub(crate) mod testing;
 
 pub use crate::machine::cpu::error::{Error, ErrorKind};
 
 pub use crate::machine::cpu::emulate::emulate;
+pub use crate::machine::cpu::history::{History, Trace};
 use crate::machine::cpu::ops::Register;
-use crate::ring_buffers;
 use std::fmt;
 
-pub type History = ring_buffers::RingBuffer<[Option<Instruction>; 256]>;
 #[derive(Debug)]
 pub struct CPU {
     pub a: u8,
@@ -35,16 +46,13 @@ pub struct CPU {
     pub debug: bool,
     pub cycles: u128,
     pub history: History,
+    pub trace: Option<Trace>,
+    pub call_stack: CallStack,
+    pub symbols: Symbols,
+    /// Everything a debug ROM has printed through BDOS.
+    pub output: String,
 }
 
-impl fmt::Display for History {
-    fn fmt(&self, f: &'_ mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        for i in self.iter() {
-            write!(f, "{:?}\n", i)?;
-        }
-        Ok(())
-    }
-}
 impl fmt::Display for CPU {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         write!(
@@ -108,12 +116,13 @@ impl<'a> CPUInterface<'a> {
     }
 
     pub fn read_1(&mut self) -> Result<u8, Error> {
-        let result = self.read(self.cpu.pc);
+        let result = self.memory.read(self.cpu.pc);
         self.advance()?;
-        result
+        Ok(result?)
     }
 
-    pub fn read(&self, offset: u16) -> Result<u8, Error> {
+    pub fn read(&mut self, offset: u16) -> Result<u8, Error> {
+        self.memory.touch(offset, Access::Read);
         Ok(self.memory.read(offset)?)
     }
 
@@ -123,13 +132,8 @@ impl<'a> CPUInterface<'a> {
     }
 
     pub fn advance(&mut self) -> Result<(), Error> {
-        let pc = (self.cpu.pc + 1) % 0x4000;
-        if self.memory.len() >= pc {
-            self.cpu.pc = pc;
-            Ok(())
-        } else {
-            Err(ErrorKind::PCOutOfRange(pc, self.memory.len()).into())
-        }
+        self.cpu.pc = self.cpu.pc.wrapping_add(1);
+        Ok(())
     }
 
     pub fn interrupt(&mut self, interrupt_num: u16) -> Result<(), Error> {
@@ -142,7 +146,11 @@ impl<'a> CPUInterface<'a> {
         self.write(sp.wrapping_sub(1), high)?;
         self.cpu.sp = sp.wrapping_sub(2);
 
-        self.cpu.pc = interrupt_num.wrapping_mul(8);
+        let target = interrupt_num.wrapping_mul(8);
+        self.cpu
+            .call_stack
+            .push(FrameKind::Interrupt, self.cpu.pc, target, self.cpu.sp);
+        self.cpu.pc = target;
 
         Ok(())
     }
@@ -172,7 +180,11 @@ pub(crate) fn new() -> CPU {
         pause: false,
         debug: false,
         cycles: 0,
-        history: ring_buffers::new([None; 256]),
+        history: History::default(),
+        trace: None,
+        call_stack: CallStack::new(),
+        symbols: Symbols::new(),
+        output: String::new(),
     }
 }
 
@@ -1,5 +1,6 @@
 #![allow(exceeding_bitshifts)]
 
+use crate::machine::cpu::syntax::Syntax;
 use std::fmt;
 
 #[repr(u8)]
@@ -264,6 +265,17 @@ pub enum OpCode {
     RST_7 = 0xff,
 }
 
+impl OpCode {
+    /// Undocumented opcodes the 8080 executes as an alias of a documented instruction.
+    pub fn is_alias(self) -> bool {
+        use self::OpCode::*;
+        match self {
+            NOP_1 | NOP_2 | NOP_3 | NOP_4 | NOP_5 | NOP_6 | NOP_7 | NOP_8 | NOP_9 | NOP_10 => true,
+            _ => false,
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum Register {
     A,
@@ -282,12 +294,12 @@ pub enum Register {
 #[derive(PartialEq, Debug, Copy, Clone)]
 pub enum Instruction {
     NOP,
-    LXI(Register, Register, u8, u8),
+    LXI(Register, u8, u8),
     JMP(u8, u8),
     JNC(u8, u8),
     PUSH(Register),
-    MVI(Register, Register, u8),
-    STAX(Register, u8, u8),
+    MVI(Register, u8),
+    STAX(Register),
     STA(u8, u8),
 
     DCR(Register),
@@ -322,6 +334,8 @@ pub enum Instruction {
 
     POP(Register),
     EI,
+    DI,
+    HLT,
     RET,
 
     MOV(Register, Register),
@@ -341,7 +355,7 @@ pub enum Instruction {
 
     XCHG,
 
-    CZ,
+    CZ(u8, u8),
     CNZ(u8, u8),
     LHLD(u8, u8),
     RZ,
@@ -375,7 +389,7 @@ pub enum Instruction {
     CPE(u8, u8),
     XRI(u8),
     RAL,
-    RST,
+    RST(u8),
     RAR,
     RIM,
     SIM,
@@ -387,8 +401,154 @@ pub enum Instruction {
     SPHL,
 }
 
+impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        use self::Instruction::*;
+        match self {
+            NOP => "NOP",
+            LXI(..) => "LXI",
+            JMP(..) => "JMP",
+            JNC(..) => "JNC",
+            PUSH(..) => "PUSH",
+            MVI(..) => "MVI",
+            STAX(..) => "STAX",
+            STA(..) => "STA",
+            DCR(..) => "DCR",
+            CALL(..) => "CALL",
+            CC(..) => "CC",
+            CPO(..) => "CPO",
+            CP(..) => "CP",
+            RPE => "RPE",
+            IN(..) => "IN",
+            RC => "RC",
+            RRC => "RRC",
+            DAA => "DAA",
+            JC(..) => "JC",
+            LDA(..) => "LDA",
+            ANA(..) => "ANA",
+            JZ(..) => "JZ",
+            CPI(..) => "CPI",
+            ADI(..) => "ADI",
+            XRA(..) => "XRA",
+            JNZ(..) => "JNZ",
+            JM(..) => "JM",
+            POP(..) => "POP",
+            EI => "EI",
+            DI => "DI",
+            HLT => "HLT",
+            RET => "RET",
+            MOV(..) => "MOV",
+            INX(..) => "INX",
+            DCX(..) => "DCX",
+            INR(..) => "INR",
+            SHLD(..) => "SHLD",
+            ANI(..) => "ANI",
+            RNC => "RNC",
+            RLC => "RLC",
+            DAD(..) => "DAD",
+            XCHG => "XCHG",
+            CZ(..) => "CZ",
+            CNZ(..) => "CNZ",
+            LHLD(..) => "LHLD",
+            RZ => "RZ",
+            RNZ => "RNZ",
+            CMA => "CMA",
+            SBI(..) => "SBI",
+            OUT(..) => "OUT",
+            ADD(..) => "ADD",
+            ADC(..) => "ADC",
+            SUB(..) => "SUB",
+            ACI(..) => "ACI",
+            ORA(..) => "ORA",
+            XTHL => "XTHL",
+            PCHL => "PCHL",
+            STC => "STC",
+            CMP(..) => "CMP",
+            SUI(..) => "SUI",
+            ORI(..) => "ORI",
+            LDAX(..) => "LDAX",
+            SBB(..) => "SBB",
+            CNC(..) => "CNC",
+            JPO(..) => "JPO",
+            JPE(..) => "JPE",
+            JP(..) => "JP",
+            CM(..) => "CM",
+            CPE(..) => "CPE",
+            XRI(..) => "XRI",
+            RAL => "RAL",
+            RST(..) => "RST",
+            RAR => "RAR",
+            RIM => "RIM",
+            SIM => "SIM",
+            CMC => "CMC",
+            RM => "RM",
+            RP => "RP",
+            RPO => "RPO",
+            SPHL => "SPHL",
+        }
+    }
+
+    /// The 16 bit address operand, if the instruction has one.
+    pub fn address(&self) -> Option<u16> {
+        use self::Instruction::*;
+        match *self {
+            CALL(l, h)
+            | CC(l, h)
+            | CZ(l, h)
+            | CPO(l, h)
+            | CP(l, h)
+            | CNZ(l, h)
+            | CNC(l, h)
+            | CM(l, h)
+            | CPE(l, h)
+            | JMP(l, h)
+            | JNC(l, h)
+            | JC(l, h)
+            | JZ(l, h)
+            | JNZ(l, h)
+            | JM(l, h)
+            | JPO(l, h)
+            | JPE(l, h)
+            | JP(l, h)
+            | STA(l, h)
+            | LDA(l, h)
+            | SHLD(l, h)
+            | LHLD(l, h)
+            | LXI(_, l, h) => Some(u16::from(h) << 8 | u16::from(l)),
+            _ => None,
+        }
+    }
+
+    pub fn is_call(&self) -> bool {
+        use self::Instruction::*;
+        match self {
+            CALL(..) | CC(..) | CZ(..) | CPO(..) | CP(..) | CNZ(..) | CNC(..) | CM(..)
+            | CPE(..) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_return(&self) -> bool {
+        use self::Instruction::*;
+        match self {
+            RET | RC | RZ | RPO | RP | RNZ | RNC | RM | RPE => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_jump(&self) -> bool {
+        use self::Instruction::*;
+        match self {
+            JMP(..) | JNC(..) | JC(..) | JZ(..) | JNZ(..) | JM(..) | JPO(..) | JPE(..) | JP(..) => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &'_ mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
-        <Self as fmt::Debug>::fmt(self, f)
+        f.write_str(&Syntax::default().format(self, None))
     }
 }
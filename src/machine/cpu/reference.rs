@@ -0,0 +1,567 @@
+//! A small 8080 model written independently of `instructions.rs`, used to fuzz `emulate()`.
+//! Instructions are decoded from their bit fields and timed from a 256-entry table rather than
+//! by opcode, so a mistake in one is unlikely to be repeated in the other.
+
+/// Memory is mirrored every 16K, as it is for `Memory`.
+pub const MEMORY_SIZE: usize = 0x4000;
+
+pub const S: u8 = 0x80;
+pub const Z: u8 = 0x40;
+pub const AC: u8 = 0x10;
+pub const P: u8 = 0x04;
+pub const CY: u8 = 0x01;
+
+/// Cycles per opcode. Conditional calls and returns take 6 more when taken.
+#[rustfmt::skip]
+const CYCLES: [u8; 256] = [
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
+    4, 10, 7, 5, 5, 5, 7, 4, 4, 10, 7, 5, 5, 5, 7, 4,
+    4, 10, 16, 5, 5, 5, 7, 4, 4, 10, 16, 5, 5, 5, 7, 4,
+    4, 10, 13, 5, 10, 10, 10, 4, 4, 10, 13, 5, 5, 5, 7, 4,
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
+    5, 5, 5, 5, 5, 5, 7, 5, 5, 5, 5, 5, 5, 5, 7, 5,
+    7, 7, 7, 7, 7, 7, 7, 7, 5, 5, 5, 5, 5, 5, 7, 5,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    4, 4, 4, 4, 4, 4, 7, 4, 4, 4, 4, 4, 4, 4, 7, 4,
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11,
+    5, 10, 10, 10, 11, 11, 7, 11, 5, 10, 10, 10, 11, 17, 7, 11,
+    5, 10, 10, 18, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
+    5, 10, 10, 4, 11, 11, 7, 11, 5, 5, 10, 4, 11, 17, 7, 11,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    /// B, C, D, E, H, L, unused, A: indexed by the 3 bit register field of an opcode, with
+    /// 6 standing for memory at HL.
+    pub regs: [u8; 8],
+    pub sp: u16,
+    pub pc: u16,
+    /// Flags in PUSH PSW layout: S Z 0 AC 0 P 1 CY.
+    pub flags: u8,
+    pub int_enable: bool,
+    pub mem: Vec<u8>,
+}
+
+impl State {
+    pub fn new(mem: Vec<u8>) -> Self {
+        State {
+            regs: [0; 8],
+            sp: 0,
+            pc: 0,
+            flags: 0x02,
+            int_enable: false,
+            mem,
+        }
+    }
+
+    pub fn flag(&self, f: u8) -> bool {
+        self.flags & f != 0
+    }
+
+    fn set_flag(&mut self, f: u8, on: bool) {
+        if on {
+            self.flags |= f
+        } else {
+            self.flags &= !f
+        }
+    }
+
+    fn load(&self, addr: u16) -> u8 {
+        self.mem[addr as usize % MEMORY_SIZE]
+    }
+
+    fn store(&mut self, addr: u16, v: u8) {
+        self.mem[addr as usize % MEMORY_SIZE] = v;
+    }
+
+    fn imm8(&mut self) -> u8 {
+        let v = self.load(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        v
+    }
+
+    fn imm16(&mut self) -> u16 {
+        let l = self.imm8();
+        u16::from(self.imm8()) << 8 | u16::from(l)
+    }
+
+    fn reg(&self, r: u8) -> u8 {
+        if r == 6 {
+            self.load(self.pair(2))
+        } else {
+            self.regs[r as usize]
+        }
+    }
+
+    fn set_reg(&mut self, r: u8, v: u8) {
+        if r == 6 {
+            let hl = self.pair(2);
+            self.store(hl, v)
+        } else {
+            self.regs[r as usize] = v
+        }
+    }
+
+    /// BC, DE, HL, SP
+    fn pair(&self, rp: u8) -> u16 {
+        match rp {
+            3 => self.sp,
+            rp => {
+                u16::from(self.regs[rp as usize * 2]) << 8
+                    | u16::from(self.regs[rp as usize * 2 + 1])
+            }
+        }
+    }
+
+    fn set_pair(&mut self, rp: u8, v: u16) {
+        match rp {
+            3 => self.sp = v,
+            rp => {
+                self.regs[rp as usize * 2] = (v >> 8) as u8;
+                self.regs[rp as usize * 2 + 1] = v as u8;
+            }
+        }
+    }
+
+    fn push(&mut self, v: u16) {
+        self.sp = self.sp.wrapping_sub(2);
+        let sp = self.sp;
+        self.store(sp, v as u8);
+        self.store(sp.wrapping_add(1), (v >> 8) as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let v = u16::from(self.load(self.sp.wrapping_add(1))) << 8 | u16::from(self.load(self.sp));
+        self.sp = self.sp.wrapping_add(2);
+        v
+    }
+
+    /// NZ, Z, NC, C, PO, PE, P, M
+    fn condition(&self, ccc: u8) -> bool {
+        let f = [Z, CY, P, S][ccc as usize >> 1];
+        self.flag(f) == (ccc & 1 == 1)
+    }
+
+    fn zsp(&mut self, v: u8) {
+        self.set_flag(Z, v == 0);
+        self.set_flag(S, v & 0x80 != 0);
+        self.set_flag(P, v.count_ones() % 2 == 0);
+    }
+
+    /// ADD, ADC, SUB, SBB, ANA, XRA, ORA, CMP
+    fn alu(&mut self, op: u8, v: u8) {
+        let a = self.regs[7];
+        let carry = if op & 1 == 1 && op < 4 && self.flag(CY) {
+            1
+        } else {
+            0
+        };
+        // Subtraction adds the complement, so AC is the carry out of bit 3 of that sum.
+        let (result, cy, ac) = match op {
+            0 | 1 => {
+                let r = u16::from(a) + u16::from(v) + carry;
+                (r as u8, r > 0xff, (a & 0xf) + (v & 0xf) + carry as u8 > 0xf)
+            }
+            2 | 3 | 7 => {
+                let r = i16::from(a) - i16::from(v) - carry as i16;
+                (
+                    r as u8,
+                    r < 0,
+                    (a & 0xf) + (!v & 0xf) + (1 - carry as u8) > 0xf,
+                )
+            }
+            4 => (a & v, false, (a | v) & 0x08 != 0),
+            5 => (a ^ v, false, false),
+            _ => (a | v, false, false),
+        };
+        self.zsp(result);
+        self.set_flag(CY, cy);
+        self.set_flag(AC, ac);
+        if op != 7 {
+            self.regs[7] = result;
+        }
+    }
+
+    /// Runs one instruction, returning its cycle count, or `None` for the instructions this
+    /// model leaves out: HLT and IN/OUT, which belong to the machine rather than the cpu.
+    pub fn step(&mut self) -> Option<u8> {
+        let op = self.imm8();
+        let mut cycles = CYCLES[op as usize];
+        let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+        match (x, z) {
+            (0, 0) => (),
+            (0, 1) if y & 1 == 0 => {
+                let v = self.imm16();
+                self.set_pair(y >> 1, v)
+            }
+            (0, 1) => {
+                let r = u32::from(self.pair(2)) + u32::from(self.pair(y >> 1));
+                self.set_flag(CY, r > 0xffff);
+                self.set_pair(2, r as u16);
+            }
+            (0, 2) => match y {
+                0 | 2 => {
+                    let (addr, a) = (self.pair(y >> 1), self.regs[7]);
+                    self.store(addr, a)
+                }
+                1 | 3 => self.regs[7] = self.load(self.pair(y >> 1)),
+                4 => {
+                    let (addr, hl) = (self.imm16(), self.pair(2));
+                    self.store(addr, hl as u8);
+                    self.store(addr.wrapping_add(1), (hl >> 8) as u8);
+                }
+                5 => {
+                    let addr = self.imm16();
+                    let v = u16::from(self.load(addr.wrapping_add(1))) << 8
+                        | u16::from(self.load(addr));
+                    self.set_pair(2, v)
+                }
+                6 => {
+                    let (addr, a) = (self.imm16(), self.regs[7]);
+                    self.store(addr, a)
+                }
+                _ => {
+                    let addr = self.imm16();
+                    self.regs[7] = self.load(addr)
+                }
+            },
+            (0, 3) => {
+                let v = self.pair(y >> 1);
+                let v = if y & 1 == 0 {
+                    v.wrapping_add(1)
+                } else {
+                    v.wrapping_sub(1)
+                };
+                self.set_pair(y >> 1, v)
+            }
+            (0, 4) | (0, 5) => {
+                let v = if z == 4 {
+                    self.reg(y).wrapping_add(1)
+                } else {
+                    self.reg(y).wrapping_sub(1)
+                };
+                self.zsp(v);
+                self.set_flag(AC, if z == 4 { v & 0xf == 0 } else { v & 0xf != 0xf });
+                self.set_reg(y, v)
+            }
+            (0, 6) => {
+                let v = self.imm8();
+                self.set_reg(y, v)
+            }
+            (0, _) => {
+                let a = self.regs[7];
+                let cy = self.flag(CY);
+                match y {
+                    0 => {
+                        self.regs[7] = a.rotate_left(1);
+                        self.set_flag(CY, a & 0x80 != 0)
+                    }
+                    1 => {
+                        self.regs[7] = a.rotate_right(1);
+                        self.set_flag(CY, a & 1 != 0)
+                    }
+                    2 => {
+                        self.regs[7] = a << 1 | cy as u8;
+                        self.set_flag(CY, a & 0x80 != 0)
+                    }
+                    3 => {
+                        self.regs[7] = a >> 1 | (cy as u8) << 7;
+                        self.set_flag(CY, a & 1 != 0)
+                    }
+                    4 => {
+                        let (lsb, msb) = (a & 0xf, a >> 4);
+                        let mut add = 0;
+                        if lsb > 9 || self.flag(AC) {
+                            add |= 0x06;
+                        }
+                        if msb > 9 || cy || (msb >= 9 && lsb > 9) {
+                            add |= 0x60;
+                            self.set_flag(CY, true);
+                        }
+                        let r = a.wrapping_add(add);
+                        self.regs[7] = r;
+                        self.zsp(r);
+                        self.set_flag(AC, lsb + (add & 0xf) > 0xf);
+                    }
+                    5 => self.regs[7] = !a,
+                    6 => self.set_flag(CY, true),
+                    _ => self.set_flag(CY, !cy),
+                }
+            }
+            (1, 6) if y == 6 => return None,
+            (1, _) => {
+                let v = self.reg(z);
+                self.set_reg(y, v)
+            }
+            (2, _) => {
+                let v = self.reg(z);
+                self.alu(y, v)
+            }
+            (_, 0) => {
+                if self.condition(y) {
+                    cycles += 6;
+                    self.pc = self.pop();
+                }
+            }
+            (_, 1) => match y {
+                1 | 3 => self.pc = self.pop(),
+                5 => self.pc = self.pair(2),
+                7 => self.sp = self.pair(2),
+                6 => {
+                    let v = self.pop();
+                    self.regs[7] = (v >> 8) as u8;
+                    self.flags = (v as u8 & (S | Z | AC | P | CY)) | 0x02;
+                }
+                y => {
+                    let v = self.pop();
+                    self.set_pair(y >> 1, v)
+                }
+            },
+            (_, 2) => {
+                let addr = self.imm16();
+                if self.condition(y) {
+                    self.pc = addr
+                }
+            }
+            (_, 3) => match y {
+                0 | 1 => self.pc = self.imm16(),
+                2 | 3 => return None,
+                4 => {
+                    let (sp, hl) = (self.sp, self.pair(2));
+                    let v =
+                        u16::from(self.load(sp.wrapping_add(1))) << 8 | u16::from(self.load(sp));
+                    self.store(sp, hl as u8);
+                    self.store(sp.wrapping_add(1), (hl >> 8) as u8);
+                    self.set_pair(2, v)
+                }
+                5 => {
+                    let (de, hl) = (self.pair(1), self.pair(2));
+                    self.set_pair(1, hl);
+                    self.set_pair(2, de)
+                }
+                6 => self.int_enable = false,
+                _ => self.int_enable = true,
+            },
+            (_, 4) => {
+                let addr = self.imm16();
+                if self.condition(y) {
+                    cycles += 6;
+                    let pc = self.pc;
+                    self.push(pc);
+                    self.pc = addr
+                }
+            }
+            (_, 5) if y & 1 == 0 => {
+                let v = match y >> 1 {
+                    3 => u16::from(self.regs[7]) << 8 | u16::from(self.flags),
+                    rp => self.pair(rp),
+                };
+                self.push(v)
+            }
+            (_, 5) => {
+                let addr = self.imm16();
+                let pc = self.pc;
+                self.push(pc);
+                self.pc = addr
+            }
+            (_, 6) => {
+                let v = self.imm8();
+                self.alu(y, v)
+            }
+            _ => {
+                let pc = self.pc;
+                self.push(pc);
+                self.pc = u16::from(y) * 8
+            }
+        }
+        Some(cycles)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::cpu::testing::{self, TestInterface};
+    use crate::machine::cpu::{self, emulate, CPUInterface, Error, ErrorKind, CPU};
+    use crate::machine::memory::Memory;
+    use failure::Fail;
+    use rand::Rng;
+    use std::env;
+
+    /// Undocumented opcodes the core runs as NOPs, where a real 8080 runs JMP, RET or CALL.
+    const NOP_ALIASES: [u8; 5] = [0xcb, 0xd9, 0xdd, 0xed, 0xfd];
+
+    /// Decimal adjust, which the core runs as a NOP until it tracks AC.
+    const DAA: u8 = 0x27;
+
+    fn to_cpu(state: &State) -> CPU {
+        let mut cpu = cpu::new();
+        cpu.b = state.regs[0];
+        cpu.c = state.regs[1];
+        cpu.d = state.regs[2];
+        cpu.e = state.regs[3];
+        cpu.h = state.regs[4];
+        cpu.l = state.regs[5];
+        cpu.a = state.regs[7];
+        cpu.sp = state.sp;
+        cpu.pc = state.pc;
+        cpu.cc.s = state.flag(S);
+        cpu.cc.z = state.flag(Z);
+        cpu.cc.ac = state.flag(AC);
+        cpu.cc.p = state.flag(P);
+        cpu.cc.cy = state.flag(CY);
+        cpu.int_enable = state.int_enable as u8;
+        cpu
+    }
+
+    /// Describes the first difference between the core and the model after one instruction.
+    fn divergence(
+        cpu: &CPU,
+        memory: &Memory,
+        cycles: u8,
+        model: &State,
+        expected: u8,
+        auxiliary_carry: bool,
+    ) -> Option<String> {
+        let regs = [
+            ("a", u16::from(cpu.a), u16::from(model.regs[7])),
+            ("b", cpu.b.into(), model.regs[0].into()),
+            ("c", cpu.c.into(), model.regs[1].into()),
+            ("d", cpu.d.into(), model.regs[2].into()),
+            ("e", cpu.e.into(), model.regs[3].into()),
+            ("h", cpu.h.into(), model.regs[4].into()),
+            ("l", cpu.l.into(), model.regs[5].into()),
+            ("sp", cpu.sp, model.sp),
+            ("pc", cpu.pc, model.pc),
+            ("s", cpu.cc.s.into(), model.flag(S).into()),
+            ("z", cpu.cc.z.into(), model.flag(Z).into()),
+            ("p", cpu.cc.p.into(), model.flag(P).into()),
+            ("cy", cpu.cc.cy.into(), model.flag(CY).into()),
+            ("int_enable", cpu.int_enable.into(), model.int_enable.into()),
+            ("cycles", cycles.into(), expected.into()),
+        ];
+        if auxiliary_carry && cpu.cc.ac != model.flag(AC) {
+            return Some(format!("ac: core {}, model {}", cpu.cc.ac, model.flag(AC)));
+        }
+        for (name, got, want) in regs.iter() {
+            if got != want {
+                return Some(format!("{}: core {:#X}, model {:#X}", name, got, want));
+            }
+        }
+        (0..MEMORY_SIZE as u16)
+            .find(|a| memory.read(*a).unwrap() != model.mem[*a as usize])
+            .map(|a| {
+                format!(
+                    "memory {:#06X}: core {:#04X}, model {:#04X}",
+                    a,
+                    memory.read(a).unwrap(),
+                    model.mem[a as usize]
+                )
+            })
+    }
+
+    /// Runs random instructions from random states through both `emulate()` and the model.
+    /// Set FUZZ_ITERATIONS to run more than the default and FUZZ_SEED to vary the states.
+    #[test]
+    fn test_fuzz_against_reference() {
+        fuzz(false)
+    }
+
+    /// The core doesn't track the auxiliary carry yet, and runs DAA as a NOP, so this fails
+    /// until it does. The fuzz above leaves both AC and DAA out.
+    #[test]
+    #[ignore]
+    fn test_fuzz_auxiliary_carry() {
+        fuzz(true)
+    }
+
+    /// Fuzzes the core against the model, comparing AC and running DAA only when
+    /// `auxiliary_carry` is set.
+    fn fuzz(auxiliary_carry: bool) {
+        let iterations = env::var("FUZZ_ITERATIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(2000);
+        let mut rng = testing::rng();
+        for _ in 0..iterations {
+            let mut mem = vec![0; MEMORY_SIZE];
+            rng.fill_bytes(&mut mem);
+            let mut model = State::new(mem);
+            for r in model.regs.iter_mut() {
+                *r = rng.gen();
+            }
+            model.regs[6] = 0;
+            model.sp = rng.gen();
+            model.pc = rng.gen_range(0, MEMORY_SIZE as u16 - 3);
+            model.flags = rng.gen::<u8>() & (S | Z | AC | P | CY) | 0x02;
+            model.int_enable = rng.gen();
+            let op = model.mem[model.pc as usize];
+            if NOP_ALIASES.contains(&op) || (op == DAA && !auxiliary_carry) {
+                continue;
+            }
+
+            let mut cpu = to_cpu(&model);
+            let mut memory = testing::memory(&model.mem);
+            let mut interface = TestInterface { input: 0 };
+            let before = format!("{}", cpu);
+            let bytes: Vec<u8> = (0..3).map(|i| model.mem[model.pc as usize + i]).collect();
+
+            let expected = match model.step() {
+                Some(cycles) => cycles,
+                None => continue,
+            };
+            let result = emulate(
+                &mut CPUInterface {
+                    cpu: &mut cpu,
+                    memory: &mut memory,
+                },
+                &mut interface,
+            );
+            let cycles = match result {
+                Ok(cycles) => cycles,
+                Err(ref e) if unimplemented(e) => continue,
+                Err(e) => panic!("{:02X?} from {}: core error {}", bytes, before, e),
+            };
+            if let Some(diff) = divergence(&cpu, &memory, cycles, &model, expected, auxiliary_carry)
+            {
+                panic!("{:02X?} from {}\nfirst divergence: {}", bytes, before, diff);
+            }
+        }
+    }
+
+    /// Whether `e`, somewhere under its history and backtrace context, is an opcode the core
+    /// doesn't implement.
+    fn unimplemented(e: &Error) -> bool {
+        let mut cause: Option<&dyn Fail> = Some(e);
+        while let Some(c) = cause {
+            if let Some(ErrorKind::UnimplementedOp(_)) = c.downcast_ref::<Error>().map(Error::kind)
+            {
+                return true;
+            }
+            cause = c.cause();
+        }
+        false
+    }
+
+    #[test]
+    fn test_model() {
+        // MVI A,$3E; SUI $40; PUSH PSW; MVI A,$9B; DAA
+        let mut mem = vec![0; MEMORY_SIZE];
+        mem[..8].copy_from_slice(&[0x3e, 0x3e, 0xd6, 0x40, 0xf5, 0x3e, 0x9b, 0x27]);
+        let mut model = State::new(mem);
+        model.sp = 0x2400;
+        assert_eq!(model.step(), Some(7));
+        assert_eq!(model.step(), Some(7));
+        assert_eq!(model.regs[7], 0xfe);
+        assert!(model.flag(CY) && model.flag(S) && !model.flag(P));
+        assert_eq!(model.step(), Some(11));
+        assert_eq!(model.mem[0x23fe], S | AC | CY | 0x02);
+        assert_eq!(model.step(), Some(7));
+        assert_eq!(model.step(), Some(4));
+        assert_eq!(model.regs[7], 0x01);
+        assert!(model.flag(CY) && model.flag(AC));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::machine::cpu::ops::{Instruction, Register};
+
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub enum HexStyle {
+    /// `$1A32`
+    Dollar,
+    /// `01A32H`, as written for Intel's own assembler
+    Suffix,
+    /// `0x1A32`
+    Prefix,
+}
+
+/// Options for printing instructions as assembler source.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Syntax {
+    pub hex: HexStyle,
+    pub uppercase: bool,
+}
+
+impl Default for Syntax {
+    fn default() -> Self {
+        Syntax {
+            hex: HexStyle::Dollar,
+            uppercase: true,
+        }
+    }
+}
+
+impl Syntax {
+    pub fn byte(&self, b: u8) -> String {
+        self.hex(format!("{:02X}", b))
+    }
+
+    pub fn word(&self, w: u16) -> String {
+        self.hex(format!("{:04X}", w))
+    }
+
+    fn hex(&self, digits: String) -> String {
+        let s = match self.hex {
+            HexStyle::Dollar => format!("${}", digits),
+            HexStyle::Suffix if digits.starts_with(|c: char| c.is_ascii_alphabetic()) => {
+                format!("0{}H", digits)
+            }
+            HexStyle::Suffix => format!("{}H", digits),
+            HexStyle::Prefix => format!("0x{}", digits),
+        };
+        self.case(s)
+    }
+
+    fn case(&self, s: String) -> String {
+        if self.uppercase {
+            s
+        } else {
+            s.to_lowercase()
+        }
+    }
+
+    fn reg(&self, r: Register) -> String {
+        self.case(format!("{:?}", r))
+    }
+
+    /// Formats `inst` as assembler source. When `label` is given it replaces the instruction's
+    /// address operand.
+    pub fn format(&self, inst: &Instruction, label: Option<&str>) -> String {
+        use crate::machine::cpu::ops::Instruction::*;
+        let mnemonic = self.case(inst.mnemonic().to_owned());
+        let address = |w: u16| match label {
+            Some(label) => label.to_owned(),
+            None => self.word(w),
+        };
+        let operands = match *inst {
+            PUSH(r) | POP(r) | DCR(r) | INR(r) | INX(r) | DCX(r) | DAD(r) | ANA(r) | XRA(r)
+            | ORA(r) | ADD(r) | ADC(r) | SUB(r) | SBB(r) | CMP(r) | LDAX(r) | STAX(r) => {
+                self.reg(r)
+            }
+            MOV(d, s) => format!("{},{}", self.reg(d), self.reg(s)),
+            MVI(r, b) => format!("{},{}", self.reg(r), self.byte(b)),
+            LXI(r, _, _) => format!("{},{}", self.reg(r), address(inst.address().unwrap())),
+            CPI(b) | ADI(b) | ANI(b) | SBI(b) | ACI(b) | SUI(b) | ORI(b) | XRI(b) | IN(b)
+            | OUT(b) => self.byte(b),
+            RST(n) => n.to_string(),
+            _ => match inst.address() {
+                Some(w) => address(w),
+                None => return mnemonic,
+            },
+        };
+        format!("{} {}", mnemonic, operands)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::cpu::ops::Register::*;
+
+    #[test]
+    fn test_format() {
+        let syntax = Syntax::default();
+        assert_eq!(
+            syntax.format(&Instruction::LXI(B, 0x34, 0x12), None),
+            "LXI B,$1234"
+        );
+        assert_eq!(syntax.format(&Instruction::MVI(M, 0x20), None), "MVI M,$20");
+        assert_eq!(
+            syntax.format(&Instruction::JNZ(0x12, 0x0A), None),
+            "JNZ $0A12"
+        );
+        assert_eq!(syntax.format(&Instruction::PUSH(PSW), None), "PUSH PSW");
+        assert_eq!(syntax.format(&Instruction::RST(7), None), "RST 7");
+        assert_eq!(syntax.format(&Instruction::XCHG, None), "XCHG");
+        assert_eq!(
+            syntax.format(&Instruction::CALL(0xE6, 0x01), Some("DrawScore")),
+            "CALL DrawScore"
+        );
+    }
+
+    #[test]
+    fn test_hex_styles() {
+        let suffix = Syntax {
+            hex: HexStyle::Suffix,
+            uppercase: true,
+        };
+        assert_eq!(
+            suffix.format(&Instruction::JMP(0xD4, 0x18), None),
+            "JMP 18D4H"
+        );
+        assert_eq!(suffix.format(&Instruction::CPI(0xFF), None), "CPI 0FFH");
+
+        let lower = Syntax {
+            hex: HexStyle::Prefix,
+            uppercase: false,
+        };
+        assert_eq!(
+            lower.format(&Instruction::LXI(SP, 0x00, 0x24), None),
+            "lxi sp,0x2400"
+        );
+    }
+}
@@ -0,0 +1,329 @@
+use crate::machine::cpu::instructions::{self, OpResult};
+use crate::machine::cpu::ops::Register::*;
+use crate::machine::cpu::ops::{Instruction, OpCode};
+use crate::machine::cpu::CPUInterface;
+
+pub type Handler = fn(&mut CPUInterface) -> OpResult;
+
+/// Builds the `Instruction` for an opcode from its (up to two) operand bytes.
+pub type Decoder = fn(u8, u8) -> Instruction;
+
+#[derive(Copy, Clone)]
+pub enum Exec {
+    Op(Handler),
+    /// IN and OUT, which go to the `MachineInterface`.
+    In,
+    Out,
+    Unimplemented,
+}
+
+/// Everything about an opcode that doesn't depend on its operands. Execution and the
+/// disassembler both read from this table.
+#[derive(Copy, Clone)]
+pub struct OpInfo {
+    pub code: OpCode,
+    pub mnemonic: &'static str,
+    pub length: u8,
+    /// Cycles taken, or for conditional calls and returns, cycles when not taken.
+    pub cycles: u8,
+    pub decode: Decoder,
+    pub exec: Exec,
+}
+
+lazy_static! {
+    static ref TABLE: Vec<Option<OpInfo>> = build();
+}
+
+pub fn op(code: u8) -> Option<&'static OpInfo> {
+    TABLE[code as usize].as_ref()
+}
+
+fn entry(code: OpCode, length: u8, cycles: u8, decode: Decoder, exec: Exec) -> OpInfo {
+    OpInfo {
+        code,
+        mnemonic: decode(0, 0).mnemonic(),
+        length,
+        cycles,
+        decode,
+        exec,
+    }
+}
+
+#[rustfmt::skip]
+fn build() -> Vec<Option<OpInfo>> {
+    use crate::machine::cpu::ops::Instruction as I;
+
+    let mut table: Vec<Option<OpInfo>> = vec![None; 256];
+
+    macro_rules! op {
+        ($code:ident, $len:expr, $cycles:expr, $decode:expr, $exec:expr) => {
+            table[OpCode::$code as usize] = Some(entry(
+                OpCode::$code,
+                $len,
+                $cycles,
+                $decode,
+                Exec::Op($exec),
+            ));
+        };
+    }
+
+    // One entry per register for single-byte instructions taking a register, with `$m_cycles`
+    // when the register is M.
+    macro_rules! regs {
+        ($inst:path, $cycles:expr, $m_cycles:expr, |$c:ident, $r:ident| $body:expr;
+         $($code:ident $reg:ident),*) => {
+            $(op!(
+                $code,
+                1,
+                if $reg == M { $m_cycles } else { $cycles },
+                |_, _| $inst($reg),
+                |$c| {
+                    let $r = $reg;
+                    $body
+                }
+            );)*
+        };
+    }
+
+    macro_rules! mov {
+        ($($code:ident $d:ident $s:ident),*) => {
+            $(op!(
+                $code,
+                1,
+                if $d == M || $s == M { 7 } else { 5 },
+                |_, _| I::MOV($d, $s),
+                |c| instructions::mov($d, $s, c)
+            );)*
+        };
+    }
+
+    let nop: Handler = |c| {
+        c.advance()?;
+        Ok(4)
+    };
+    for code in &[
+        OpCode::NOP_0,
+        OpCode::NOP_1,
+        OpCode::NOP_2,
+        OpCode::NOP_3,
+        OpCode::NOP_4,
+        OpCode::NOP_5,
+        OpCode::NOP_6,
+        OpCode::NOP_7,
+        OpCode::NOP_8,
+        OpCode::NOP_9,
+        OpCode::NOP_10,
+    ] {
+        table[*code as usize] = Some(entry(*code, 1, 4, |_, _| I::NOP, Exec::Op(nop)));
+    }
+    // DAA needs the auxiliary carry, which isn't tracked yet.
+    op!(DAA, 1, 4, |_, _| I::DAA, nop);
+    op!(RIM, 1, 4, |_, _| I::RIM, nop);
+    table[OpCode::SIM as usize] = Some(entry(OpCode::SIM, 1, 4, |_, _| I::SIM, Exec::Unimplemented));
+    table[OpCode::HLT as usize] = Some(entry(OpCode::HLT, 1, 7, |_, _| I::HLT, Exec::Unimplemented));
+
+    // Data transfer
+    mov!(
+        MOV_A_A A A, MOV_A_B A B, MOV_A_C A C, MOV_A_D A D, MOV_A_E A E, MOV_A_H A H, MOV_A_L A L, MOV_A_M A M,
+        MOV_B_A B A, MOV_B_B B B, MOV_B_C B C, MOV_B_D B D, MOV_B_E B E, MOV_B_H B H, MOV_B_L B L, MOV_B_M B M,
+        MOV_C_A C A, MOV_C_B C B, MOV_C_C C C, MOV_C_D C D, MOV_C_E C E, MOV_C_H C H, MOV_C_L C L, MOV_C_M C M,
+        MOV_D_A D A, MOV_D_B D B, MOV_D_C D C, MOV_D_D D D, MOV_D_E D E, MOV_D_H D H, MOV_D_L D L, MOV_D_M D M,
+        MOV_E_A E A, MOV_E_B E B, MOV_E_C E C, MOV_E_D E D, MOV_E_E E E, MOV_E_H E H, MOV_E_L E L, MOV_E_M E M,
+        MOV_H_A H A, MOV_H_B H B, MOV_H_C H C, MOV_H_D H D, MOV_H_E H E, MOV_H_H H H, MOV_H_L H L, MOV_H_M H M,
+        MOV_L_A L A, MOV_L_B L B, MOV_L_C L C, MOV_L_D L D, MOV_L_E L E, MOV_L_H L H, MOV_L_L L L, MOV_L_M L M,
+        MOV_M_A M A, MOV_M_B M B, MOV_M_C M C, MOV_M_D M D, MOV_M_E M E, MOV_M_H M H, MOV_M_L M L
+    );
+    op!(MVI_A, 2, 7, |b, _| I::MVI(A, b), |c| instructions::mvi(A, c));
+    op!(MVI_B, 2, 7, |b, _| I::MVI(B, b), |c| instructions::mvi(B, c));
+    op!(MVI_C, 2, 7, |b, _| I::MVI(C, b), |c| instructions::mvi(C, c));
+    op!(MVI_D, 2, 7, |b, _| I::MVI(D, b), |c| instructions::mvi(D, c));
+    op!(MVI_E, 2, 7, |b, _| I::MVI(E, b), |c| instructions::mvi(E, c));
+    op!(MVI_H, 2, 7, |b, _| I::MVI(H, b), |c| instructions::mvi(H, c));
+    op!(MVI_L, 2, 7, |b, _| I::MVI(L, b), |c| instructions::mvi(L, c));
+    op!(MVI_M, 2, 10, |b, _| I::MVI(M, b), |c| instructions::mvi(M, c));
+    op!(LXI_B, 3, 10, |l, h| I::LXI(B, l, h), |c| instructions::lxi(B, c));
+    op!(LXI_D, 3, 10, |l, h| I::LXI(D, l, h), |c| instructions::lxi(D, c));
+    op!(LXI_H, 3, 10, |l, h| I::LXI(H, l, h), |c| instructions::lxi(H, c));
+    op!(LXI_SP, 3, 10, |l, h| I::LXI(SP, l, h), |c| instructions::lxi(SP, c));
+    regs!(I::LDAX, 7, 7, |c, r| instructions::ldax(r, c); LDAX_B B, LDAX_D D);
+    regs!(I::STAX, 7, 7, |c, r| instructions::stax(r, c); STAX_B B, STAX_D D);
+    op!(LDA, 3, 13, I::LDA, instructions::lda);
+    op!(STA, 3, 13, I::STA, instructions::sta);
+    op!(LHLD, 3, 16, I::LHLD, instructions::lhld);
+    op!(SHLD, 3, 16, I::SHLD, instructions::shld);
+    op!(XCHG, 1, 4, |_, _| I::XCHG, instructions::xchg);
+
+    // Arithmetic
+    regs!(I::ADD, 4, 7, |c, r| instructions::add(r, c);
+        ADD_A A, ADD_B B, ADD_C C, ADD_D D, ADD_E E, ADD_H H, ADD_L L, ADD_M M);
+    regs!(I::ADC, 4, 7, |c, r| instructions::adc(r, c);
+        ADC_A A, ADC_B B, ADC_C C, ADC_D D, ADC_E E, ADC_H H, ADC_L L, ADC_M M);
+    regs!(I::SUB, 4, 7, |c, r| instructions::sub(r, c);
+        SUB_A A, SUB_B B, SUB_C C, SUB_D D, SUB_E E, SUB_H H, SUB_L L, SUB_M M);
+    regs!(I::SBB, 4, 7, |c, r| instructions::sbb(r, c);
+        SBB_A A, SBB_B B, SBB_C C, SBB_D D, SBB_E E, SBB_H H, SBB_L L, SBB_M M);
+    regs!(I::INR, 5, 10, |c, r| instructions::inr(r, c);
+        INR_A A, INR_B B, INR_C C, INR_D D, INR_E E, INR_H H, INR_L L, INR_M M);
+    regs!(I::DCR, 5, 10, |c, r| instructions::dcr(r, c);
+        DCR_A A, DCR_B B, DCR_C C, DCR_D D, DCR_E E, DCR_H H, DCR_L L, DCR_M M);
+    regs!(I::INX, 5, 5, |c, r| instructions::inx(r, c); INX_B B, INX_D D, INX_H H, INX_SP SP);
+    regs!(I::DCX, 5, 5, |c, r| instructions::dcx(r, c); DCX_B B, DCX_D D, DCX_H H, DCX_SP SP);
+    regs!(I::DAD, 10, 10, |c, r| instructions::dad(r, c); DAD_B B, DAD_D D, DAD_H H, DAD_SP SP);
+    op!(ADI, 2, 7, |b, _| I::ADI(b), instructions::adi);
+    op!(ACI, 2, 7, |b, _| I::ACI(b), instructions::aci);
+    op!(SUI, 2, 7, |b, _| I::SUI(b), instructions::sui);
+    op!(SBI, 2, 7, |b, _| I::SBI(b), instructions::sbi);
+
+    // Logical
+    regs!(I::ANA, 4, 7, |c, r| instructions::log(r, c, |a, b| a & b);
+        ANA_A A, ANA_B B, ANA_C C, ANA_D D, ANA_E E, ANA_H H, ANA_L L, ANA_M M);
+    regs!(I::XRA, 4, 7, |c, r| instructions::log(r, c, |a, b| a ^ b);
+        XRA_A A, XRA_B B, XRA_C C, XRA_D D, XRA_E E, XRA_H H, XRA_L L, XRA_M M);
+    regs!(I::ORA, 4, 7, |c, r| instructions::log(r, c, |a, b| a | b);
+        ORA_A A, ORA_B B, ORA_C C, ORA_D D, ORA_E E, ORA_H H, ORA_L L, ORA_M M);
+    regs!(I::CMP, 4, 7, |c, r| instructions::cmp(r, c);
+        CMP_A A, CMP_B B, CMP_C C, CMP_D D, CMP_E E, CMP_H H, CMP_L L, CMP_M M);
+    op!(ANI, 2, 7, |b, _| I::ANI(b), instructions::ani);
+    op!(XRI, 2, 7, |b, _| I::XRI(b), |c| instructions::logi(
+        c,
+        7,
+        |a, b| a ^ b
+    ));
+    op!(ORI, 2, 7, |b, _| I::ORI(b), |c| instructions::logi(
+        c,
+        7,
+        |a, b| a | b
+    ));
+    op!(CPI, 2, 7, |b, _| I::CPI(b), instructions::cpi);
+    op!(RLC, 1, 4, |_, _| I::RLC, instructions::rlc);
+    op!(RRC, 1, 4, |_, _| I::RRC, instructions::rrc);
+    op!(RAL, 1, 4, |_, _| I::RAL, instructions::ral);
+    op!(RAR, 1, 4, |_, _| I::RAR, instructions::rar);
+    op!(CMA, 1, 4, |_, _| I::CMA, |c| {
+        c.advance()?;
+        c.cpu.a = !c.cpu.a;
+        Ok(4)
+    });
+    op!(STC, 1, 4, |_, _| I::STC, |c| {
+        c.advance()?;
+        c.cpu.cc.cy = true;
+        Ok(4)
+    });
+    op!(CMC, 1, 4, |_, _| I::CMC, instructions::cmc);
+
+    // Branch
+    op!(JMP, 3, 10, I::JMP, |c| instructions::jmp_if(c, |_| true));
+    op!(JNZ, 3, 10, I::JNZ, |c| instructions::jmp_if(c, |s| !s.cpu.cc.z));
+    op!(JZ, 3, 10, I::JZ, |c| instructions::jmp_if(c, |s| s.cpu.cc.z));
+    op!(JNC, 3, 10, I::JNC, |c| instructions::jmp_if(c, |s| !s.cpu.cc.cy));
+    op!(JC, 3, 10, I::JC, |c| instructions::jmp_if(c, |s| s.cpu.cc.cy));
+    op!(JPO, 3, 10, I::JPO, |c| instructions::jmp_if(c, |s| !s.cpu.cc.p));
+    op!(JPE, 3, 10, I::JPE, |c| instructions::jmp_if(c, |s| s.cpu.cc.p));
+    op!(JP, 3, 10, I::JP, |c| instructions::jmp_if(c, |s| !s.cpu.cc.s));
+    op!(JM, 3, 10, I::JM, |c| instructions::jmp_if(c, |s| s.cpu.cc.s));
+    op!(CALL, 3, 17, I::CALL, instructions::call);
+    op!(CNZ, 3, 11, I::CNZ, |c| instructions::call_if(c, |s| !s.cpu.cc.z));
+    op!(CZ, 3, 11, I::CZ, |c| instructions::call_if(c, |s| s.cpu.cc.z));
+    op!(CNC, 3, 11, I::CNC, |c| instructions::call_if(c, |s| !s.cpu.cc.cy));
+    op!(CC, 3, 11, I::CC, |c| instructions::call_if(c, |s| s.cpu.cc.cy));
+    op!(CPO, 3, 11, I::CPO, |c| instructions::call_if(c, |s| !s.cpu.cc.p));
+    op!(CPE, 3, 11, I::CPE, |c| instructions::call_if(c, |s| s.cpu.cc.p));
+    op!(CP, 3, 11, I::CP, |c| instructions::call_if(c, |s| !s.cpu.cc.s));
+    op!(CM, 3, 11, I::CM, |c| instructions::call_if(c, |s| s.cpu.cc.s));
+    op!(RET, 1, 10, |_, _| I::RET, instructions::ret);
+    op!(RNZ, 1, 5, |_, _| I::RNZ, |c| instructions::ret_if(c, |s| !s.cpu.cc.z));
+    op!(RZ, 1, 5, |_, _| I::RZ, |c| instructions::ret_if(c, |s| s.cpu.cc.z));
+    op!(RNC, 1, 5, |_, _| I::RNC, |c| instructions::ret_if(c, |s| !s.cpu.cc.cy));
+    op!(RC, 1, 5, |_, _| I::RC, |c| instructions::ret_if(c, |s| s.cpu.cc.cy));
+    op!(RPO, 1, 5, |_, _| I::RPO, |c| instructions::ret_if(c, |s| !s.cpu.cc.p));
+    op!(RPE, 1, 5, |_, _| I::RPE, |c| instructions::ret_if(c, |s| s.cpu.cc.p));
+    op!(RP, 1, 5, |_, _| I::RP, |c| instructions::ret_if(c, |s| !s.cpu.cc.s));
+    op!(RM, 1, 5, |_, _| I::RM, |c| instructions::ret_if(c, |s| s.cpu.cc.s));
+    op!(RST_0, 1, 11, |_, _| I::RST(0), |c| instructions::rst(0, c));
+    op!(RST_1, 1, 11, |_, _| I::RST(1), |c| instructions::rst(1, c));
+    op!(RST_2, 1, 11, |_, _| I::RST(2), |c| instructions::rst(2, c));
+    op!(RST_3, 1, 11, |_, _| I::RST(3), |c| instructions::rst(3, c));
+    op!(RST_4, 1, 11, |_, _| I::RST(4), |c| instructions::rst(4, c));
+    op!(RST_5, 1, 11, |_, _| I::RST(5), |c| instructions::rst(5, c));
+    op!(RST_6, 1, 11, |_, _| I::RST(6), |c| instructions::rst(6, c));
+    op!(RST_7, 1, 11, |_, _| I::RST(7), |c| instructions::rst(7, c));
+    op!(PCHL, 1, 5, |_, _| I::PCHL, instructions::pchl);
+
+    // Stack, I/O and machine control
+    regs!(I::PUSH, 11, 11, |c, r| instructions::push(r, c); PUSH_B B, PUSH_D D, PUSH_H H, PUSH_PSW PSW);
+    regs!(I::POP, 10, 10, |c, r| instructions::pop(r, c); POP_B B, POP_D D, POP_H H, POP_PSW PSW);
+    op!(XTHL, 1, 18, |_, _| I::XTHL, instructions::xthl);
+    op!(SPHL, 1, 5, |_, _| I::SPHL, instructions::sphl);
+    op!(EI, 1, 4, |_, _| I::EI, |c| {
+        c.advance()?;
+        c.cpu.int_enable = 1;
+        Ok(4)
+    });
+    op!(DI, 1, 4, |_, _| I::DI, |c| {
+        c.advance()?;
+        c.cpu.int_enable = 0;
+        Ok(4)
+    });
+    table[OpCode::IN as usize] = Some(entry(OpCode::IN, 2, 10, |b, _| I::IN(b), Exec::In));
+    table[OpCode::OUT as usize] = Some(entry(OpCode::OUT, 2, 10, |b, _| I::OUT(b), Exec::Out));
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::cpu::testing;
+
+    #[test]
+    fn test_table() {
+        for code in 0..=255u8 {
+            let info = op(code).unwrap_or_else(|| panic!("no entry for {:#04X}", code));
+            assert_eq!(info.code as u8, code);
+        }
+
+        let call = op(0xcd).unwrap();
+        assert_eq!((call.mnemonic, call.length, call.cycles), ("CALL", 3, 17));
+        assert_eq!((call.decode)(0xe6, 0x01), Instruction::CALL(0xe6, 0x01));
+
+        let mov = op(0x7e).unwrap();
+        assert_eq!((mov.mnemonic, mov.length, mov.cycles), ("MOV", 1, 7));
+        assert_eq!((mov.decode)(0, 0), Instruction::MOV(A, M));
+    }
+
+    /// Conditional calls and returns take 6 cycles more than `OpInfo::cycles` when taken.
+    const CONDITIONAL: [&str; 16] = [
+        "CNZ", "CZ", "CNC", "CC", "CPO", "CPE", "CP", "CM", "RNZ", "RZ", "RNC", "RC", "RPO", "RPE",
+        "RP", "RM",
+    ];
+
+    /// Runs every opcode with all flags clear and then all set, which takes each condition
+    /// one way and then the other, and checks the cycles handlers return against the table.
+    #[test]
+    fn test_cycles() {
+        for code in 0..=255u8 {
+            let info = op(code).unwrap();
+            if let Exec::Unimplemented = info.exec {
+                continue;
+            }
+            // Jumps and calls go to $3000, outside the program, which stops the run.
+            let program = &[code, 0x00, 0x30][..info.length as usize];
+            let mut cycles: Vec<u128> = ["!z !s !p !cy", "z s p cy"]
+                .iter()
+                .map(|flags| {
+                    let given = format!("sp=$2400 hl=$2400 {}", flags);
+                    testing::run_bytes(0x100, program, &given)
+                        .unwrap_or_else(|e| panic!("{}: {}", info.mnemonic, e))
+                        .cycles
+                })
+                .collect();
+            cycles.sort();
+            let expected = u128::from(info.cycles);
+            if CONDITIONAL.contains(&info.mnemonic) {
+                assert_eq!(cycles, vec![expected, expected + 6], "{}", info.mnemonic);
+            } else {
+                assert_eq!(cycles, vec![expected, expected], "{}", info.mnemonic);
+            }
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Compact per-instruction cpu tests. A case is a short program, given as assembler source or
+//! raw bytes, plus the state before and after it runs, written as whitespace separated fields:
+//!
+//! ```text
+//! a=$14 b=$22 hl=$2400 sp=$23FE [$2400]=$7F cy !z ie in=$01
+//! ```
+//!
+//! Registers and register pairs take values, `[addr]` is a memory byte, a flag name (`z`, `s`,
+//! `p`, `cy`, `ac`, `ie`) means set and `!flag` means clear, and `in` is what an `IN` reads.
+//! Only the fields named in the expected state are checked.
+
+use crate::machine::assembler::{assemble, evaluate};
+use crate::machine::cpu::{self, emulate, CPUInterface, Error, CPU};
+use crate::machine::memory::Memory;
+use crate::machine::memory_map::{MemoryMap, RegionKind};
+use crate::machine::{MachineEvent, MachineInterface};
+use rand::{SeedableRng, StdRng};
+use std::env;
+use std::time::Instant;
+
+const MEMORY_SIZE: usize = 0x4000;
+
+/// Seed for randomized tests when FUZZ_SEED isn't set.
+const DEFAULT_SEED: usize = 0x8080;
+
+/// Programs that jump back into themselves are stopped after this many instructions.
+const MAX_STEPS: usize = 1000;
+
+/// Answers every `IN` with `input` and ignores everything else.
+pub struct TestInterface {
+    pub input: u8,
+}
+
+impl MachineInterface for TestInterface {
+    fn handle_in(
+        &mut self,
+        cpu: &mut CPUInterface,
+        _port: u8,
+    ) -> Result<(), crate::machine::Error> {
+        cpu.cpu.a = self.input;
+        Ok(())
+    }
+
+    fn handle_out(
+        &mut self,
+        _cpu: &mut CPUInterface,
+        _port: u8,
+    ) -> Result<(), crate::machine::Error> {
+        Ok(())
+    }
+
+    fn handle_interrupt(
+        &mut self,
+        _now: &Instant,
+        _cpu: &mut CPUInterface,
+    ) -> Result<(), crate::machine::Error> {
+        Ok(())
+    }
+
+    fn handle_event(&mut self, _evt: MachineEvent) -> Result<(), crate::machine::Error> {
+        Ok(())
+    }
+
+    /// 16K of RAM, repeated through the address space.
+    fn memory_map() -> MemoryMap {
+        MemoryMap::new()
+            .region(0x0000, 0x3fff, RegionKind::Ram)
+            .region(0x4000, 0xffff, RegionKind::Mirror(0x3fff))
+    }
+
+    fn new() -> Self {
+        TestInterface { input: 0 }
+    }
+}
+
+/// `TestInterface` memory holding `contents` from address 0.
+pub fn memory(contents: &[u8]) -> Memory {
+    let mut memory = Memory::with_map(&TestInterface::memory_map()).unwrap();
+    memory.load(0, contents);
+    memory
+}
+
+/// A generator for randomized tests, seeded from FUZZ_SEED or `DEFAULT_SEED` so that a failure
+/// can be rerun. The seed is printed, which the test harness shows for failing tests.
+pub fn rng() -> StdRng {
+    let seed = env::var("FUZZ_SEED")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_SEED);
+    println!("FUZZ_SEED={}", seed);
+    StdRng::from_seed(&[seed][..])
+}
+
+#[derive(Debug, PartialEq)]
+enum Field {
+    Reg(String, u16),
+    Mem(u16, u8),
+    Flag(String, bool),
+    In(u8),
+}
+
+fn value(s: &str) -> u16 {
+    match evaluate(s) {
+        Some(v) if (0..=0xffff).contains(&v) => v as u16,
+        _ => panic!("bad value in test state: {}", s),
+    }
+}
+
+fn parse(state: &str) -> Vec<Field> {
+    state
+        .split_whitespace()
+        .map(|field| {
+            let field = field.to_lowercase();
+            match field.find('=') {
+                Some(i) if field.starts_with('[') && field[..i].ends_with(']') => {
+                    Field::Mem(value(&field[1..i - 1]), value(&field[i + 1..]) as u8)
+                }
+                Some(i) if &field[..i] == "in" => Field::In(value(&field[i + 1..]) as u8),
+                Some(i) => Field::Reg(field[..i].to_owned(), value(&field[i + 1..])),
+                None if field.starts_with('!') => Field::Flag(field[1..].to_owned(), false),
+                None => Field::Flag(field, true),
+            }
+        })
+        .collect()
+}
+
+fn get_reg(cpu: &CPU, name: &str) -> u16 {
+    let pair = |h: u8, l: u8| u16::from(h) << 8 | u16::from(l);
+    match name {
+        "a" => cpu.a.into(),
+        "b" => cpu.b.into(),
+        "c" => cpu.c.into(),
+        "d" => cpu.d.into(),
+        "e" => cpu.e.into(),
+        "h" => cpu.h.into(),
+        "l" => cpu.l.into(),
+        "bc" => pair(cpu.b, cpu.c),
+        "de" => pair(cpu.d, cpu.e),
+        "hl" => pair(cpu.h, cpu.l),
+        "sp" => cpu.sp,
+        "pc" => cpu.pc,
+        _ => panic!("unknown register in test state: {}", name),
+    }
+}
+
+fn set_reg(cpu: &mut CPU, name: &str, v: u16) {
+    let (h, l) = ((v >> 8) as u8, (v & 0xff) as u8);
+    if v > 0xff && name.len() == 1 {
+        panic!("value too large for {}: {:#X}", name, v);
+    }
+    match name {
+        "a" => cpu.a = l,
+        "b" => cpu.b = l,
+        "c" => cpu.c = l,
+        "d" => cpu.d = l,
+        "e" => cpu.e = l,
+        "h" => cpu.h = l,
+        "l" => cpu.l = l,
+        "bc" => {
+            cpu.b = h;
+            cpu.c = l
+        }
+        "de" => {
+            cpu.d = h;
+            cpu.e = l
+        }
+        "hl" => {
+            cpu.h = h;
+            cpu.l = l
+        }
+        "sp" => cpu.sp = v,
+        "pc" => cpu.pc = v,
+        _ => panic!("unknown register in test state: {}", name),
+    }
+}
+
+fn flag<'a>(cpu: &'a mut CPU, name: &str) -> &'a mut bool {
+    match name {
+        "z" => &mut cpu.cc.z,
+        "s" => &mut cpu.cc.s,
+        "p" => &mut cpu.cc.p,
+        "cy" => &mut cpu.cc.cy,
+        "ac" => &mut cpu.cc.ac,
+        _ => panic!("unknown flag in test state: {}", name),
+    }
+}
+
+pub struct Outcome {
+    pub cpu: CPU,
+    pub memory: Memory,
+    pub cycles: u128,
+}
+
+/// Loads `program` at `origin` and runs it from there until the pc leaves the program.
+pub fn run_bytes(origin: u16, program: &[u8], given: &str) -> Result<Outcome, Error> {
+    let mut buf = vec![0; MEMORY_SIZE];
+    buf[origin as usize..origin as usize + program.len()].copy_from_slice(program);
+    let mut cpu = cpu::new();
+    cpu.pc = origin;
+    let mut input = 0;
+    for field in parse(given) {
+        match field {
+            Field::Reg(name, v) => set_reg(&mut cpu, &name, v),
+            Field::Mem(addr, v) => buf[addr as usize] = v,
+            Field::Flag(ref name, v) if name == "ie" => cpu.int_enable = v as u8,
+            Field::Flag(name, v) => *flag(&mut cpu, &name) = v,
+            Field::In(v) => input = v,
+        }
+    }
+
+    let mut memory = memory(&buf);
+    let mut interface = TestInterface { input };
+    let end = origin as usize + program.len();
+    let mut cycles = 0;
+    {
+        let mut state = CPUInterface {
+            cpu: &mut cpu,
+            memory: &mut memory,
+        };
+        for _ in 0..MAX_STEPS {
+            let pc = state.cpu.pc as usize;
+            if pc < origin as usize || pc >= end {
+                break;
+            }
+            cycles += u128::from(emulate(&mut state, &mut interface)?);
+        }
+    }
+    Ok(Outcome {
+        cpu,
+        memory,
+        cycles,
+    })
+}
+
+/// Assembles `source` and runs it like `run_bytes`.
+pub fn run(source: &str, given: &str) -> Result<Outcome, Error> {
+    let assembly = assemble(source).unwrap_or_else(|e| panic!("{}: {}", source, e));
+    run_bytes(assembly.origin, &assembly.bytes, given)
+}
+
+fn verify(name: &str, outcome: Result<Outcome, Error>, expected: &str, cycles: u128) {
+    let mut outcome = outcome.unwrap_or_else(|e| panic!("{}: cpu error: {}", name, e));
+    let mut mismatches = vec![];
+    for field in parse(expected) {
+        let (want, got) = match field {
+            Field::Reg(ref reg, v) => (v, get_reg(&outcome.cpu, reg)),
+            Field::Mem(addr, v) => (v.into(), outcome.memory.read(addr).unwrap().into()),
+            Field::Flag(ref name, v) if name == "ie" => {
+                (v as u16, u16::from(outcome.cpu.int_enable != 0))
+            }
+            Field::Flag(ref name, v) => (v as u16, *flag(&mut outcome.cpu, name) as u16),
+            Field::In(_) => panic!("`in` can't be checked"),
+        };
+        if want != got {
+            mismatches.push(format!("{:?}: got {:#X}", field, got));
+        }
+    }
+    if outcome.cycles != cycles {
+        mismatches.push(format!(
+            "cycles: expected {}, got {}",
+            cycles, outcome.cycles
+        ));
+    }
+    if !mismatches.is_empty() {
+        panic!("{}:\n  {}", name, mismatches.join("\n  "));
+    }
+}
+
+/// Runs `source` from `given` and checks the `expected` fields and the total cycle count.
+pub fn check(source: &str, given: &str, expected: &str, cycles: u128) {
+    verify(source, run(source, given), expected, cycles)
+}
+
+pub fn check_bytes(program: &[u8], given: &str, expected: &str, cycles: u128) {
+    verify(
+        &format!("{:02X?}", program),
+        run_bytes(0, program, given),
+        expected,
+        cycles,
+    )
+}
@@ -0,0 +1,226 @@
+use crate::machine::coverage::Hint;
+use crate::machine::cpu::disassembler::disassemble;
+use crate::machine::cpu::ops::{Instruction, OpCode};
+use crate::machine::cpu::syntax::Syntax;
+use crate::machine::memory::Memory;
+use crate::machine::symbols::Symbols;
+use num::FromPrimitive;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Reset and RST/interrupt vectors, where every 8080 program can be entered.
+pub const VECTORS: [u16; 8] = [0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38];
+
+/// The most bytes put on a single `DB` line.
+const DB_WIDTH: usize = 8;
+
+/// Which bytes of a rom were reached as instructions by following control flow.
+pub struct CodeMap {
+    instructions: BTreeMap<u16, (Instruction, u16)>,
+    code: Vec<bool>,
+}
+
+impl CodeMap {
+    pub fn instruction(&self, pc: u16) -> Option<&(Instruction, u16)> {
+        self.instructions.get(&pc)
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = (&u16, &(Instruction, u16))> {
+        self.instructions.iter()
+    }
+
+    pub fn is_code(&self, pc: u16) -> bool {
+        self.code.get(pc as usize).cloned().unwrap_or(false)
+    }
+}
+
+/// Where control can go after `inst`: an explicit target, and whether it can fall through to
+/// the next instruction.
+fn flow(inst: &Instruction) -> (Option<u16>, bool) {
+    match inst {
+        Instruction::JMP(..) => (inst.address(), false),
+        Instruction::RET | Instruction::PCHL => (None, false),
+        Instruction::RST(n) => (Some(u16::from(*n) * 8), true),
+        inst if inst.is_jump() || inst.is_call() => (inst.address(), true),
+        _ => (None, true),
+    }
+}
+
+/// Follows control flow from `seeds`, never decoding inside the `data` ranges. Seeds are
+/// explored in order, so earlier seeds win when two paths disagree about alignment.
+pub fn trace(mem: &Memory, seeds: &[u16], data: &[(u16, u16)]) -> CodeMap {
+    let len = mem.len();
+    let mut map = CodeMap {
+        instructions: BTreeMap::new(),
+        code: vec![false; len],
+    };
+    let mut blocked = vec![false; len];
+    for &(start, end) in data {
+        let (start, end) = (
+            usize::from(start),
+            usize::from(end).min(len.saturating_sub(1)),
+        );
+        if start <= end && start < len {
+            blocked[start..=end].iter_mut().for_each(|b| *b = true);
+        }
+    }
+
+    let mut work: Vec<u16> = seeds.iter().rev().cloned().collect();
+    while let Some(pc) = work.pop() {
+        let start = pc as usize;
+        if start >= len || map.code[start] || blocked[start] {
+            continue;
+        }
+        let alias = mem
+            .read(pc)
+            .ok()
+            .and_then(OpCode::from_u8)
+            .map_or(true, OpCode::is_alias);
+        let (inst, inc) = match disassemble(mem, pc) {
+            Ok(r) if !alias => r,
+            _ => continue,
+        };
+        let end = start + inc as usize;
+        if end > len || (start..end).any(|i| map.code[i] || blocked[i]) {
+            continue;
+        }
+        for i in start..end {
+            map.code[i] = true;
+        }
+        map.instructions.insert(pc, (inst, inc));
+
+        let (target, falls_through) = flow(&inst);
+        if falls_through {
+            work.push(pc.wrapping_add(inc));
+        }
+        if let Some(target) = target {
+            work.push(target);
+        }
+    }
+    map
+}
+
+/// Seeds and data ranges for `trace`, from the vectors plus an optional hint file.
+pub fn seeds(hints: &[(u16, u16, Hint)]) -> (Vec<u16>, Vec<(u16, u16)>) {
+    let mut seeds = VECTORS.to_vec();
+    let mut data = vec![];
+    for (start, end, hint) in hints {
+        match hint {
+            Hint::Code => seeds.push(*start),
+            Hint::Data => data.push((*start, *end)),
+        }
+    }
+    (seeds, data)
+}
+
+fn preview(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| {
+            if b.is_ascii_graphic() || *b == b' ' {
+                *b as char
+            } else {
+                '.'
+            }
+        })
+        .collect()
+}
+
+/// Assembler source for `mem`. Traced instructions are disassembled, everything else is
+/// emitted as `DB` lines. Call and jump targets without a symbol get a generated label, and
+/// labels that don't land on a line are emitted as `EQU`s.
+pub fn listing(mem: &Memory, map: &CodeMap, symbols: &mut Symbols, syntax: &Syntax) -> String {
+    for (_, (inst, _)) in map.instructions() {
+        symbols.label(inst);
+    }
+
+    let len = mem.len();
+    let mut lines = vec![];
+    let mut pc = 0;
+    while pc < len {
+        if let Some((inst, inc)) = map.instruction(pc as u16) {
+            lines.push((pc as u16, symbols.format_with(syntax, inst), *inc, None));
+            pc += usize::from(*inc);
+            continue;
+        }
+
+        let mut end = pc + 1;
+        while end < len
+            && end - pc < DB_WIDTH
+            && !map.is_code(end as u16)
+            && symbols.get(end as u16).is_none()
+        {
+            end += 1;
+        }
+        let bytes: Vec<u8> = (pc..end).map(|a| mem.read(a as u16).unwrap_or(0)).collect();
+        let text: Vec<String> = bytes.iter().map(|b| syntax.byte(*b)).collect();
+        lines.push((
+            pc as u16,
+            format!("DB {}", text.join(",")),
+            (end - pc) as u16,
+            Some(preview(&bytes)),
+        ));
+        pc = end;
+    }
+
+    let mut s = String::new();
+    let starts: BTreeSet<u16> = lines.iter().map(|(pc, _, _, _)| *pc).collect();
+    for (addr, symbol) in symbols.iter() {
+        if !starts.contains(addr) {
+            s.push_str(&format!("{} EQU {}\n", symbol.name, syntax.word(*addr)));
+        }
+    }
+    s.push_str(&format!("        ORG {}\n", syntax.word(0)));
+    for (pc, text, inc, preview) in lines {
+        if let Some(symbol) = symbols.get(pc) {
+            match &symbol.comment {
+                Some(comment) => s.push_str(&format!("{}: ; {}\n", symbol.name, comment)),
+                None => s.push_str(&format!("{}:\n", symbol.name)),
+            }
+        }
+        let comment = match preview {
+            Some(preview) => format!("'{}'", preview),
+            None => (0..inc)
+                .map(|i| format!("{:02X}", mem.read(pc.wrapping_add(i)).unwrap_or(0)))
+                .collect::<Vec<String>>()
+                .join(" "),
+        };
+        s.push_str(&format!("        {:<24}; {:04X}  {}\n", text, pc, comment));
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_skips_data() {
+        // JMP $0005; two bytes of text; CALL $0009; HLT; RET
+        let mem = Memory::new(vec![
+            0xc3, 0x05, 0x00, b'H', b'I', 0xcd, 0x09, 0x00, 0x76, 0xc9,
+        ]);
+        let map = trace(&mem, &[0], &[]);
+        assert!(map.is_code(0));
+        assert!(!map.is_code(3));
+        assert!(!map.is_code(4));
+        assert!(map.instruction(5).is_some());
+        assert!(map.instruction(8).is_some());
+        assert!(map.instruction(9).is_some());
+
+        let mut symbols = Symbols::new();
+        let s = listing(&mem, &map, &mut symbols, &Syntax::default());
+        assert!(s.contains("DB $48,$49"));
+        assert!(s.contains("'HI'"));
+        assert!(s.contains("JMP L_0005"));
+    }
+
+    #[test]
+    fn test_trace_data_hint() {
+        let mem = Memory::new(vec![0x00, 0x00, 0xc9]);
+        let (seeds, data) = seeds(&[(1, 1, Hint::Data)]);
+        let map = trace(&mem, &seeds, &data);
+        assert!(map.is_code(0));
+        assert!(!map.is_code(1));
+        assert!(!map.is_code(2));
+    }
+}
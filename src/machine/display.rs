@@ -1,27 +1,142 @@
 //! Basic hello world example.
-use crossbeam_channel::Receiver;
 use ggez::conf;
 use ggez::event;
 use ggez::graphics;
 use ggez::{Context, GameResult};
+use std::collections::BTreeMap;
 use std::env;
 use std::path;
+use std::sync::{Arc, Mutex};
 
 pub const FB_SIZE: usize = 0x4000 - 0x2400;
+/// Bytes per row of VRAM, one scanline of the unrotated screen.
+pub const ROW_BYTES: usize = 256 / 8;
+pub const ROWS: usize = FB_SIZE / ROW_BYTES;
 type DisplayBuf = [u8; 4 * 224 * 256];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Orientation {
+    /// VRAM rows are screen rows, 256 pixels wide and 224 high.
+    Upright,
+    /// The monitor is turned a quarter turn counterclockwise, so VRAM rows are screen
+    /// columns, 224 pixels wide and 256 high.
+    Rotated,
+}
+
+/// A coloured gel stuck over `x.0..x.1`, `y.0..y.1` of the screen.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    pub x: (usize, usize),
+    pub y: (usize, usize),
+    pub color: [u8; 3],
+}
+
+/// How a machine's monitor shows VRAM. Pixels outside the overlay are white.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Screen {
+    pub orientation: Orientation,
+    pub overlay: &'static [Band],
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen {
+            orientation: Orientation::Rotated,
+            overlay: &[],
+        }
+    }
+}
+
+impl Screen {
+    pub fn width(&self) -> usize {
+        match self.orientation {
+            Orientation::Upright => 256,
+            Orientation::Rotated => 224,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self.orientation {
+            Orientation::Upright => 224,
+            Orientation::Rotated => 256,
+        }
+    }
+
+    /// Where bit `x` of VRAM row `row` ends up on screen.
+    fn position(&self, row: usize, x: usize) -> (usize, usize) {
+        match self.orientation {
+            Orientation::Upright => (x, row),
+            Orientation::Rotated => (row, 255 - x),
+        }
+    }
+
+    fn color(&self, x: usize, y: usize) -> [u8; 3] {
+        self.overlay
+            .iter()
+            .find(|b| b.x.0 <= x && x < b.x.1 && b.y.0 <= y && y < b.y.1)
+            .map_or([0xff; 3], |b| b.color)
+    }
+}
+
+/// VRAM rows that changed since the last frame, by row.
+#[derive(Debug, Default)]
+pub struct Frame {
+    pub rows: BTreeMap<usize, [u8; ROW_BYTES]>,
+}
+
+impl Frame {
+    /// Takes `newer`'s rows on top of these.
+    fn merge(&mut self, newer: Frame) {
+        self.rows.extend(newer.rows);
+    }
+}
+
+/// Holds at most one frame between the emulation thread and the display. Sending while the
+/// display still hasn't taken the last frame merges the two, so nothing queues up and no row
+/// is lost.
+pub fn frame_channel() -> (FrameSender, FrameReceiver) {
+    let slot = Arc::new(Mutex::new(None));
+    (FrameSender(slot.clone()), FrameReceiver(slot))
+}
+
+pub struct FrameSender(Arc<Mutex<Option<Frame>>>);
+
+impl FrameSender {
+    pub fn send(&self, frame: Frame) {
+        let mut slot = self.0.lock().unwrap();
+        match slot.as_mut() {
+            Some(pending) => pending.merge(frame),
+            None => *slot = Some(frame),
+        }
+    }
+}
+
+pub struct FrameReceiver(Arc<Mutex<Option<Frame>>>);
+
+impl FrameReceiver {
+    pub fn try_recv(&self) -> Option<Frame> {
+        self.0.lock().unwrap().take()
+    }
+}
+
 // First we make a structure to contain the game's state
 struct Display {
     frames: usize,
     buf: DisplayBuf,
-    receiver: Receiver<[u8; FB_SIZE]>,
-    event_sender: Sender<MachineEvent>,
+    screen: Screen,
+    scale: f32,
+    image: Option<graphics::Image>,
+    receiver: FrameReceiver,
+    controller: Controller,
 }
 
 impl Display {
     fn new(
         _ctx: &mut Context,
-        receiver: Receiver<[u8; FB_SIZE]>,
-        event_sender: Sender<MachineEvent>,
+        screen: Screen,
+        scale: u32,
+        receiver: FrameReceiver,
+        controller: Controller,
     ) -> GameResult<Display> {
         // The ttf file will be in your resources directory. Later, we
         // will mount that directory so we can omit it in the path here.
@@ -29,37 +144,34 @@ impl Display {
         let s = Display {
             frames: 0,
             buf,
+            screen,
+            scale: scale as f32,
+            image: None,
             receiver,
-            event_sender,
+            controller,
         };
         Ok(s)
     }
 
-    fn update_buf(&mut self, fb: [u8; FB_SIZE]) {
-        update_buf(&mut self.buf, fb)
+    fn update_buf(&mut self, frame: &Frame) {
+        for (row, bytes) in &frame.rows {
+            update_row(&mut self.buf, &self.screen, *row, bytes)
+        }
     }
 }
 
-fn update_buf(buf: &mut DisplayBuf, fb: [u8; FB_SIZE]) {
-    for i in 0..224 {
-        let mut j = 0;
-        while j < 256 {
-            let pixel_offset = (i * (256 / 8)) + j / 8;
-            let pixel = fb[pixel_offset];
-
-            let mut offset = (255 - j) * (224 * 4) + (i * 4);
-            for p in 0..8 {
-                let p1 = if 0 != (pixel & (1 << p)) { 0xff } else { 0x00 };
-
-                buf[offset] = p1;
-                buf[offset + 1] = p1;
-                buf[offset + 2] = p1;
-                buf[offset + 3] = 0xff;
-
-                offset = offset.wrapping_sub(224 * 4)
-            }
-            j += 8;
-        }
+/// Expands VRAM row `i` into `buf`, least significant bit first.
+fn update_row(buf: &mut DisplayBuf, screen: &Screen, i: usize, row: &[u8; ROW_BYTES]) {
+    for x in 0..256 {
+        let (sx, sy) = screen.position(i, x);
+        let color = if 0 != row[x / 8] & (1 << (x % 8)) {
+            screen.color(sx, sy)
+        } else {
+            [0; 3]
+        };
+        let offset = (sy * screen.width() + sx) * 4;
+        buf[offset..offset + 3].copy_from_slice(&color);
+        buf[offset + 3] = 0xff;
     }
 }
 
@@ -76,13 +188,27 @@ impl event::EventHandler for Display {
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         graphics::clear(ctx);
 
-        if let Some(buf) = self.receiver.try_recv() {
-            self.update_buf(buf);
+        // The image is only rebuilt when the emulator sent changes.
+        if let Some(frame) = self.receiver.try_recv() {
+            self.update_buf(&frame);
+            let mut image = graphics::Image::from_rgba8(
+                ctx,
+                self.screen.width() as u16,
+                self.screen.height() as u16,
+                &self.buf,
+            )?;
+            image.set_filter(graphics::FilterMode::Nearest);
+            self.image = Some(image);
         }
-        let image = graphics::Image::from_rgba8(ctx, 224, 256, &self.buf)?;
 
-        let dest_point = graphics::Point2::new(0.0, 0.0);
-        graphics::draw(ctx, &image, dest_point, 0.0)?;
+        if let Some(image) = &self.image {
+            let param = graphics::DrawParam {
+                dest: graphics::Point2::new(0.0, 0.0),
+                scale: graphics::Point2::new(self.scale, self.scale),
+                ..Default::default()
+            };
+            graphics::draw_ex(ctx, image, param)?;
+        }
 
         // Drawables are drawn from their top-left corner.
         graphics::present(ctx);
@@ -96,14 +222,26 @@ impl event::EventHandler for Display {
     }
 
     fn key_down_event(&mut self, _ctx: &mut Context, keycode: Keycode, keymod: Mod, repeat: bool) {
-        self.event_sender.send(MachineEvent::KeyDown {
+        if HOTKEYS.contains(&keycode) {
+            if let (Some(control), false) = (hotkey(keycode, true), repeat) {
+                self.controller.speed(control);
+            }
+            return;
+        }
+        self.controller.send(MachineEvent::KeyDown {
             code: keycode,
             keymod,
             repeat,
         })
     }
     fn key_up_event(&mut self, _ctx: &mut Context, keycode: Keycode, keymod: Mod, repeat: bool) {
-        self.event_sender.send(MachineEvent::KeyUp {
+        if HOTKEYS.contains(&keycode) {
+            if let Some(control) = hotkey(keycode, false) {
+                self.controller.speed(control);
+            }
+            return;
+        }
+        self.controller.send(MachineEvent::KeyUp {
             code: keycode,
             keymod,
             repeat,
@@ -119,16 +257,44 @@ impl event::EventHandler for Display {
 // * Second, create a `ggez::game::Game` object which will
 // do the work of creating our MainState and running our game.
 // * Then, just call `game.run()` which runs the `Game` mainloop.
-use crate::machine::display;
-use crate::machine::MachineEvent;
-use crossbeam_channel::Sender;
+use crate::machine::speed::SpeedControl;
+use crate::machine::{Controller, MachineEvent};
 use ggez::event::Keycode;
 use ggez::event::Mod;
 
-pub fn run(recv: Receiver<[u8; display::FB_SIZE]>, sender: Sender<MachineEvent>) -> GameResult<()> {
+/// Keys the window keeps for itself rather than passing to the machine: P pauses, N advances
+/// a frame while paused, Tab runs four times as fast while held, and - and = halve and
+/// double the speed.
+const HOTKEYS: &[Keycode] = &[
+    Keycode::P,
+    Keycode::N,
+    Keycode::Tab,
+    Keycode::Minus,
+    Keycode::Equals,
+];
+
+fn hotkey(code: Keycode, down: bool) -> Option<SpeedControl> {
+    match (code, down) {
+        (Keycode::P, true) => Some(SpeedControl::TogglePause),
+        (Keycode::N, true) => Some(SpeedControl::AdvanceFrame),
+        (Keycode::Tab, down) => Some(SpeedControl::FastForward(down)),
+        (Keycode::Minus, true) => Some(SpeedControl::Slower),
+        (Keycode::Equals, true) => Some(SpeedControl::Faster),
+        _ => None,
+    }
+}
+
+/// Opens a window `scale` times the size of `screen` and shows frames from `recv` until it's
+/// closed.
+pub fn run(
+    screen: Screen,
+    scale: u32,
+    recv: FrameReceiver,
+    controller: Controller,
+) -> GameResult<()> {
     let mut c = conf::Conf::new();
-    c.window_mode.width = 224;
-    c.window_mode.height = 256;
+    c.window_mode.width = screen.width() as u32 * scale;
+    c.window_mode.height = screen.height() as u32 * scale;
 
     let ctx = &mut Context::load_from_conf("helloworld", "ggez", c)?;
     // We add the CARGO_MANIFEST_DIR/resources to the filesystem's path
@@ -139,7 +305,7 @@ pub fn run(recv: Receiver<[u8; display::FB_SIZE]>, sender: Sender<MachineEvent>)
         ctx.filesystem.mount(&path, true);
     }
 
-    let state = &mut Display::new(ctx, recv, sender)?;
+    let state = &mut Display::new(ctx, screen, scale, recv, controller)?;
     if let Err(e) = event::run(ctx, state) {
         println!("Error encountered: {}", e);
     } else {
@@ -147,3 +313,58 @@ pub fn run(recv: Receiver<[u8; display::FB_SIZE]>, sender: Sender<MachineEvent>)
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(rows: &[(usize, u8)]) -> Frame {
+        Frame {
+            rows: rows.iter().map(|&(i, b)| (i, [b; ROW_BYTES])).collect(),
+        }
+    }
+
+    #[test]
+    fn test_frame_channel() {
+        let (tx, rx) = frame_channel();
+        assert!(rx.try_recv().is_none());
+        tx.send(frame(&[(0, 1), (5, 1)]));
+        tx.send(frame(&[(5, 2), (7, 2)]));
+        let rows = rx.try_recv().unwrap().rows;
+        assert_eq!(rows.keys().collect::<Vec<_>>(), vec![&0, &5, &7]);
+        assert_eq!(rows[&0][0], 1);
+        assert_eq!(rows[&5][0], 2);
+        assert!(rx.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_update_row() {
+        const RED: Band = Band {
+            x: (0, 8),
+            y: (0, 256),
+            color: [0xff, 0, 0],
+        };
+        let screen = Screen {
+            orientation: Orientation::Rotated,
+            overlay: &[RED],
+        };
+        let mut buf = [0; 4 * 224 * 256];
+        let mut row = [0; ROW_BYTES];
+        row[0] = 0x01;
+        update_row(&mut buf, &screen, 3, &row);
+        let pixel = |buf: &DisplayBuf, x: usize, y: usize, width: usize| {
+            buf[(y * width + x) * 4..][..4].to_vec()
+        };
+        assert_eq!(pixel(&buf, 3, 255, 224), vec![0xff, 0, 0, 0xff]);
+        assert_eq!(pixel(&buf, 3, 254, 224), vec![0, 0, 0, 0xff]);
+
+        let screen = Screen {
+            orientation: Orientation::Upright,
+            overlay: &[],
+        };
+        row[31] = 0x80;
+        update_row(&mut buf, &screen, 3, &row);
+        assert_eq!(pixel(&buf, 0, 3, 256), vec![0xff; 4]);
+        assert_eq!(pixel(&buf, 255, 3, 256), vec![0xff; 4]);
+    }
+}
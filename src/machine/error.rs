@@ -1,8 +1,7 @@
 use crate::machine::cpu;
 use crate::machine::memory;
 use std::any::Any;
-use std::error;
-use std::sync;
+use std::io;
 
 #[derive(Fail, Debug)]
 pub enum Error {
@@ -10,22 +9,16 @@ pub enum Error {
     MemoryError(#[fail(cause)] memory::Error),
     #[fail(display = "CPUEror {}", _0)]
     CPUError(#[fail(cause)] cpu::Error),
-    #[fail(display = "LockErr")]
-    LockErr,
     #[fail(display = "{}", _0)]
     GameError(#[fail(cause)] ggez::GameError),
+    #[fail(display = "IoError {}", _0)]
+    IoError(#[fail(cause)] io::Error),
 
     #[fail(display = "{}", _0)]
     ForeignError(String),
 }
 
-impl error::Error for Box<Error> {}
-
-impl<T> From<sync::PoisonError<T>> for Error {
-    fn from(_err: sync::PoisonError<T>) -> Self {
-        Error::LockErr
-    }
-}
+impl std::error::Error for Box<Error> {}
 
 impl From<cpu::Error> for Error {
     fn from(err: cpu::Error) -> Self {
@@ -45,6 +38,12 @@ impl From<ggez::GameError> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::IoError(err)
+    }
+}
+
 impl From<Box<Any + Send>> for Error {
     fn from(_err: Box<Any + Send>) -> Self {
         Error::ForeignError("Foreign error".to_string())
@@ -1,67 +1,635 @@
+use crate::machine::coverage::{Access, Coverage};
 use crate::machine::display;
+use crate::machine::memory_map::{MemoryMap, RegionKind};
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+
+/// Granularity of the memory map and of code write tracking.
+pub const PAGE_SIZE: usize = 0x100;
+
+const PAGES: usize = 0x10000 / PAGE_SIZE;
+
+/// Where a page of the address space lives. Mirrors are resolved to the page they repeat, so
+/// `kind` is never `Mirror`.
+#[derive(Clone, Copy, Debug)]
+struct Page {
+    kind: RegionKind,
+    /// Index into `buf`.
+    base: usize,
+    /// Address of the page after resolving mirrors. Same as `base` unless banked.
+    home: usize,
+    /// Index into `banks`.
+    bank: Option<usize>,
+}
+
+/// A `Banked` region. Bank 0 lives at the region's own address, the others after the
+/// 64 KiB address space.
+#[derive(Debug)]
+struct Bank {
+    start: usize,
+    len: usize,
+    count: u8,
+    selected: u8,
+    /// Where bank 1 starts in `buf`.
+    extra: usize,
+}
+
+impl Bank {
+    fn base(&self) -> usize {
+        match self.selected {
+            0 => self.start,
+            n => self.extra + usize::from(n - 1) * self.len,
+        }
+    }
+}
+
+/// What happens when the program writes to ROM.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WritePolicy {
+    /// Drop the write, as the hardware does.
+    Ignore,
+    /// Drop the write and print a warning the first time each address is written.
+    Log,
+    /// Fail with `Error::WriteToRom`.
+    Error,
+}
+
+/// Hardware that answers reads and writes to part of an `Io` region. Offsets are relative to
+/// the start of the range the device is attached at.
+pub trait Device: Send {
+    fn read(&self, offset: u16) -> u8;
+    fn write(&mut self, offset: u16, data: u8);
+}
+
+/// A `Device` made of a read and a write callback.
+pub struct Callbacks<R, W> {
+    pub read: R,
+    pub write: W,
+}
+
+impl<R, W> Device for Callbacks<R, W>
+where
+    R: Fn(u16) -> u8 + Send,
+    W: FnMut(u16, u8) + Send,
+{
+    fn read(&self, offset: u16) -> u8 {
+        (self.read)(offset)
+    }
+
+    fn write(&mut self, offset: u16, data: u8) {
+        (self.write)(offset, data)
+    }
+}
+
+struct Attached {
+    start: usize,
+    end: usize,
+    device: Box<dyn Device>,
+}
+
+/// Which bytes of a run of physical memory have been written, see `Memory::watch_writes`.
+#[derive(Debug)]
+struct WriteWatch {
+    start: usize,
+    written: Vec<bool>,
+}
+
+impl fmt::Debug for Attached {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Device({:#06X}-{:#06X})", self.start, self.end)
+    }
+}
 
 #[derive(Debug)]
-pub struct Memory(Vec<u8>);
+pub struct Memory {
+    buf: Vec<u8>,
+    pages: Vec<Page>,
+    len: usize,
+    vram: Option<usize>,
+    /// VRAM rows written since the last `vram_changes`.
+    vram_dirty: Vec<bool>,
+    write_policy: WritePolicy,
+    /// ROM addresses already warned about under `WritePolicy::Log`.
+    logged_writes: HashSet<u16>,
+    devices: Vec<Attached>,
+    banks: Vec<Bank>,
+    coverage: Option<Coverage>,
+    /// Pages the block cache has decoded code from.
+    code_pages: Vec<bool>,
+    /// Watched pages written to since the block cache last asked.
+    written_code: Vec<usize>,
+    /// Indexed by id; `None` once unwatched.
+    write_watches: Vec<Option<WriteWatch>>,
+}
 
 #[derive(Fail, Debug)]
 pub enum Error {
     #[fail(display = "Out of range access: {}, len: {}", _0, _1)]
     OutOfRangeAccess(usize, usize),
 
-    #[fail(display = "Tried to write to rom: {}", _0)]
-    WriteToRom(usize),
+    #[fail(display = "Tried to write to rom: {:#06X}", _0)]
+    WriteToRom(u16),
+
+    #[fail(display = "Access to unmapped address {:#06X}", _0)]
+    Unmapped(u16),
+
+    #[fail(display = "No device at {:#06X}", _0)]
+    NoDevice(u16),
 
-    #[fail(display = "LockError")]
-    LockErr,
+    #[fail(display = "Bad memory map: {}", _0)]
+    BadMap(String),
+
+    #[fail(display = "No bank {} at {:#06X}", _1, _0)]
+    NoBank(u16, u8),
 }
 
 impl Memory {
+    /// `vec` as RAM from address 0, with the rest of the address space unmapped. `len` is
+    /// the length of `vec` rather than of the address space.
     pub fn new(vec: Vec<u8>) -> Self {
-        Memory(vec)
+        let len = vec.len().min(0x10000);
+        let mut map = MemoryMap::new();
+        if len > 0 {
+            let end = (len + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE - 1;
+            map = map.region(0, end as u16, RegionKind::Ram);
+        }
+        let mut memory = Memory::with_map(&map).expect("flat map is valid");
+        memory.load(0, &vec[..len]);
+        memory.len = len;
+        memory
+    }
+
+    /// Zeroed memory laid out by `map`.
+    pub fn with_map(map: &MemoryMap) -> Result<Self, Error> {
+        map.validate()?;
+        let mut pages: Vec<Page> = (0..PAGES)
+            .map(|i| Page {
+                kind: RegionKind::Unmapped,
+                base: i * PAGE_SIZE,
+                home: i * PAGE_SIZE,
+                bank: None,
+            })
+            .collect();
+        let mut banks = vec![];
+        let mut len = PAGES * PAGE_SIZE;
+        let mirrors = map.regions().iter().filter(|r| match r.kind {
+            RegionKind::Mirror(_) => true,
+            _ => false,
+        });
+        let others = map.regions().iter().filter(|r| match r.kind {
+            RegionKind::Mirror(_) => false,
+            _ => true,
+        });
+        for region in others.chain(mirrors) {
+            if let RegionKind::Banked(count) = region.kind {
+                banks.push(Bank {
+                    start: usize::from(region.start),
+                    len: region.len(),
+                    count,
+                    selected: 0,
+                    extra: len,
+                });
+                len += usize::from(count - 1) * region.len();
+            }
+            let first = usize::from(region.start) / PAGE_SIZE;
+            for page in first..first + region.len() / PAGE_SIZE {
+                pages[page] = match region.kind {
+                    RegionKind::Mirror(mask) => {
+                        pages[((page * PAGE_SIZE) & usize::from(mask)) / PAGE_SIZE]
+                    }
+                    kind => Page {
+                        kind,
+                        base: page * PAGE_SIZE,
+                        home: page * PAGE_SIZE,
+                        bank: match kind {
+                            RegionKind::Banked(_) => Some(banks.len() - 1),
+                            _ => None,
+                        },
+                    },
+                };
+            }
+        }
+        let vram = map
+            .regions()
+            .iter()
+            .find(|r| r.kind == RegionKind::Vram && r.len() == display::FB_SIZE)
+            .map(|r| usize::from(r.start));
+
+        Ok(Memory {
+            buf: vec![0; len],
+            pages,
+            len: PAGES * PAGE_SIZE,
+            vram,
+            vram_dirty: vec![true; display::ROWS],
+            write_policy: WritePolicy::Ignore,
+            logged_writes: HashSet::new(),
+            devices: vec![],
+            banks,
+            coverage: None,
+            code_pages: vec![false; len / PAGE_SIZE],
+            written_code: vec![],
+            write_watches: vec![],
+        })
+    }
+
+    /// Copies `bytes` in from `start` whatever the regions are, for loading ROMs.
+    pub fn load(&mut self, start: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate().take(0x10000 - usize::from(start)) {
+            let physical = self.physical(start + i as u16);
+            self.buf[physical] = *byte;
+        }
+    }
+
+    /// Makes `start..=end` read only, along with anything mirroring it. The range must be
+    /// page aligned.
+    pub fn protect(&mut self, start: u16, end: u16) -> Result<(), Error> {
+        let (start, end) = (usize::from(start), usize::from(end) + 1);
+        if start % PAGE_SIZE != 0 || end % PAGE_SIZE != 0 || start >= end {
+            return Err(Error::BadMap(format!(
+                "rom region {:#06X}-{:#06X} is not page aligned",
+                start,
+                end - 1
+            )));
+        }
+        for page in self.pages.iter_mut() {
+            let mapped = page.kind != RegionKind::Unmapped && page.kind != RegionKind::Io;
+            if mapped && start <= page.base && page.base < end {
+                page.kind = RegionKind::Rom;
+            }
+        }
+        Ok(())
+    }
+
+    /// Routes accesses to `start..=end` to `device`. The range has to be inside `Io` regions
+    /// and not overlap another device; mirrors of it reach the device too.
+    pub fn attach(&mut self, start: u16, end: u16, device: Box<dyn Device>) -> Result<(), Error> {
+        let bad = |why: &str| {
+            Err(Error::BadMap(format!(
+                "device at {:#06X}-{:#06X} {}",
+                start, end, why
+            )))
+        };
+        if start > end {
+            return bad("ends before it starts");
+        }
+        let (start, end) = (usize::from(start), usize::from(end));
+        if (start / PAGE_SIZE..=end / PAGE_SIZE).any(|page| {
+            self.pages[page].kind != RegionKind::Io || self.pages[page].base != page * PAGE_SIZE
+        }) {
+            return bad("is outside the io regions");
+        }
+        if self
+            .devices
+            .iter()
+            .any(|d| d.start <= end && start <= d.end)
+        {
+            return bad("overlaps another device");
+        }
+        self.devices.push(Attached { start, end, device });
+        Ok(())
+    }
+
+    fn device(&self, physical: usize) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| d.start <= physical && physical <= d.end)
+    }
+
+    /// Switches the banked region holding `address` to `bank`. Cached code from the bank
+    /// being switched out is invalidated as if it had been written to.
+    pub fn select_bank(&mut self, address: u16, bank: u8) -> Result<(), Error> {
+        let i = match self.pages[usize::from(address) / PAGE_SIZE].bank {
+            Some(i) if bank < self.banks[i].count => i,
+            _ => return Err(Error::NoBank(address, bank)),
+        };
+        if self.banks[i].selected == bank {
+            return Ok(());
+        }
+        self.banks[i].selected = bank;
+        let (start, base) = (self.banks[i].start, self.banks[i].base());
+        for page in self.pages.iter_mut().filter(|p| p.bank == Some(i)) {
+            let old = page.base / PAGE_SIZE;
+            if self.code_pages[old] {
+                self.code_pages[old] = false;
+                self.written_code.push(old);
+            }
+            page.base = base + page.home - start;
+        }
+        Ok(())
+    }
+
+    /// The start address and selected bank of each banked region, for saving and showing
+    /// machine state.
+    pub fn banks(&self) -> Vec<(u16, u8)> {
+        self.banks
+            .iter()
+            .map(|b| (b.start as u16, b.selected))
+            .collect()
+    }
+
+    pub fn set_write_policy(&mut self, policy: WritePolicy) {
+        self.write_policy = policy;
+    }
+
+    /// Index into `buf` that `offset` resolves to through mirrors.
+    fn physical(&self, offset: u16) -> usize {
+        let offset = usize::from(offset);
+        self.pages[offset / PAGE_SIZE].base | (offset % PAGE_SIZE)
+    }
+
+    /// Marks the page holding `offset` as containing cached code and returns it. The next
+    /// write to the page is reported by `take_written_code`.
+    pub fn watch_code(&mut self, offset: u16) -> usize {
+        let page = self.physical(offset) / PAGE_SIZE;
+        self.code_pages[page] = true;
+        page
+    }
+
+    pub fn code_written(&self) -> bool {
+        !self.written_code.is_empty()
+    }
+
+    /// Watched pages written to since the last call. They stop being watched until
+    /// `watch_code` is called for them again.
+    pub fn take_written_code(&mut self) -> Vec<usize> {
+        mem::replace(&mut self.written_code, vec![])
+    }
+
+    /// Starts tracking which bytes of `start..=end` get written. The range has to be
+    /// contiguous in physical memory. Returns the id to ask `all_written` with.
+    pub fn watch_writes(&mut self, start: u16, end: u16) -> Result<usize, Error> {
+        let bad = |why: &str| {
+            Err(Error::BadMap(format!(
+                "watched range {:#06X}-{:#06X} {}",
+                start, end, why
+            )))
+        };
+        if start > end {
+            return bad("ends before it starts");
+        }
+        let base = self.physical(start);
+        if (start..=end).any(|a| self.physical(a) != base + usize::from(a - start)) {
+            return bad("is not contiguous");
+        }
+        self.write_watches.push(Some(WriteWatch {
+            start: base,
+            written: vec![false; usize::from(end - start) + 1],
+        }));
+        Ok(self.write_watches.len() - 1)
+    }
+
+    /// Whether every byte watched under `id` has been written since `watch_writes`. False
+    /// after `unwatch_writes`.
+    pub fn all_written(&self, id: usize) -> bool {
+        match &self.write_watches[id] {
+            Some(watch) => watch.written.iter().all(|&w| w),
+            None => false,
+        }
+    }
+
+    /// Stops tracking writes for `id`, so writes no longer pay for it.
+    pub fn unwatch_writes(&mut self, id: usize) {
+        self.write_watches[id] = None;
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new(self.len));
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// Records an access for coverage tracking, a no-op unless coverage is enabled. Banked
+    /// accesses are recorded by address, whichever bank is selected.
+    pub fn touch(&mut self, offset: u16, access: Access) {
+        if let Some(coverage) = self.coverage.as_mut() {
+            let home = self.pages[usize::from(offset) / PAGE_SIZE].home;
+            coverage.mark((home | (usize::from(offset) % PAGE_SIZE)) as u16, access);
+        }
     }
 
     pub fn read(&self, offset: u16) -> Result<u8, Error> {
-        let offset = offset as usize % 0x4000;
-        let mem = &self.0;
-        if mem.len() > offset {
-            Ok(mem[offset])
-        } else {
-            Err(Error::OutOfRangeAccess(offset, mem.len()))
+        let page = self.pages[usize::from(offset) / PAGE_SIZE];
+        match page.kind {
+            RegionKind::Unmapped => Err(Error::Unmapped(offset)),
+            RegionKind::Io => {
+                let physical = self.physical(offset);
+                match self.device(physical) {
+                    Some(i) => {
+                        let attached = &self.devices[i];
+                        Ok(attached.device.read((physical - attached.start) as u16))
+                    }
+                    None => Err(Error::NoDevice(offset)),
+                }
+            }
+            _ => Ok(self.buf[page.base | (usize::from(offset) % PAGE_SIZE)]),
+        }
+    }
+
+    /// `start..=end` ranges of writable memory, leaving out mirrors and ROM.
+    pub fn ram(&self) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = vec![];
+        for (i, page) in self.pages.iter().enumerate() {
+            let writable = match page.kind {
+                RegionKind::Ram | RegionKind::Vram | RegionKind::Banked(_) => true,
+                _ => false,
+            };
+            if !writable || page.home != i * PAGE_SIZE {
+                continue;
+            }
+            let (start, end) = (
+                (i * PAGE_SIZE) as u16,
+                (i * PAGE_SIZE + PAGE_SIZE - 1) as u16,
+            );
+            match ranges.last_mut() {
+                Some(last) if usize::from(last.1) + 1 == usize::from(start) => last.1 = end,
+                _ => ranges.push((start, end)),
+            }
         }
+        ranges
     }
 
-    pub fn len(&self) -> u16 {
-        self.0.len() as u16
+    /// Size of the address space, or for memory made with `new`, of its buffer.
+    pub fn len(&self) -> usize {
+        self.len
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.is_empty()
+        self.len == 0
     }
 
-    pub fn vram(&self) -> Result<[u8; display::FB_SIZE], Error> {
-        let mut v = [0; display::FB_SIZE];
-        if self.0.len() > 0x4000 {
-            v.copy_from_slice(&self.0[0x2400..0x4000]);
-            Ok(v)
+    /// The framebuffer rows written since the last call, or everything on the first call.
+    /// `None` if nothing changed or the map has no VRAM region of the right size.
+    pub fn vram_changes(&mut self) -> Option<display::Frame> {
+        let start = self.vram?;
+        let mut frame = display::Frame::default();
+        for (row, dirty) in self.vram_dirty.iter_mut().enumerate() {
+            if *dirty {
+                let offset = start + row * display::ROW_BYTES;
+                let mut bytes = [0; display::ROW_BYTES];
+                bytes.copy_from_slice(&self.buf[offset..offset + display::ROW_BYTES]);
+                frame.rows.insert(row, bytes);
+                *dirty = false;
+            }
+        }
+        if frame.rows.is_empty() {
+            None
         } else {
-            Err(Error::OutOfRangeAccess(0x4000, self.0.len()))
+            Some(frame)
         }
     }
 
+    /// Writes to ROM are handled according to the write policy.
     pub fn write(&mut self, offset: u16, data: u8) -> Result<(), Error> {
-        let offset = offset as usize % 0x4000;
-        let mem = &mut self.0;
-
-        // rom should be configured by ROM
-        if offset < 0x2000 {
-            mem[offset] = data;
-            Ok(())
-        //Err(Error::WriteToRom(offset))
-        } else if offset >= 0x4000 {
-            Err(Error::OutOfRangeAccess(offset, mem.len()))
-        } else {
-            mem[offset] = data;
-            Ok(())
+        self.touch(offset, Access::Write);
+        let page = self.pages[usize::from(offset) / PAGE_SIZE];
+        match page.kind {
+            RegionKind::Unmapped => return Err(Error::Unmapped(offset)),
+            RegionKind::Io => return self.write_device(offset, data),
+            RegionKind::Rom => return self.write_to_rom(offset, data),
+            _ => (),
         }
+        let physical = page.base | (usize::from(offset) % PAGE_SIZE);
+        if self.code_pages[physical / PAGE_SIZE] {
+            self.code_pages[physical / PAGE_SIZE] = false;
+            self.written_code.push(physical / PAGE_SIZE);
+        }
+        if let Some(start) = self.vram {
+            if start <= physical && physical < start + display::FB_SIZE {
+                self.vram_dirty[(physical - start) / display::ROW_BYTES] = true;
+            }
+        }
+        for watch in self.write_watches.iter_mut().flatten() {
+            if watch.start <= physical && physical < watch.start + watch.written.len() {
+                watch.written[physical - watch.start] = true;
+            }
+        }
+        self.buf[physical] = data;
+        Ok(())
+    }
+
+    fn write_device(&mut self, offset: u16, data: u8) -> Result<(), Error> {
+        let physical = self.physical(offset);
+        match self.device(physical) {
+            Some(i) => {
+                let attached = &mut self.devices[i];
+                attached
+                    .device
+                    .write((physical - attached.start) as u16, data);
+                Ok(())
+            }
+            None => Err(Error::NoDevice(offset)),
+        }
+    }
+
+    fn write_to_rom(&mut self, offset: u16, data: u8) -> Result<(), Error> {
+        match self.write_policy {
+            WritePolicy::Ignore => Ok(()),
+            WritePolicy::Log => {
+                if self.logged_writes.insert(offset) {
+                    println!(
+                        "warning: ignored write of {:#04X} to rom at {:#06X}",
+                        data, offset
+                    );
+                }
+                Ok(())
+            }
+            WritePolicy::Error => Err(Error::WriteToRom(offset)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_devices() {
+        let map = MemoryMap::new()
+            .region(0x0000, 0x00ff, RegionKind::Ram)
+            .region(0x0100, 0x01ff, RegionKind::Io)
+            .region(0x0200, 0x03ff, RegionKind::Mirror(0x01ff));
+        let mut memory = Memory::with_map(&map).unwrap();
+        let latch = Arc::new(Mutex::new(vec![]));
+        let written = latch.clone();
+        memory
+            .attach(
+                0x0110,
+                0x011f,
+                Box::new(Callbacks {
+                    read: |offset| offset as u8 | 0x80,
+                    write: move |offset, data| written.lock().unwrap().push((offset, data)),
+                }),
+            )
+            .unwrap();
+
+        assert_eq!(memory.read(0x0112).unwrap(), 0x82);
+        assert_eq!(memory.read(0x0312).unwrap(), 0x82);
+        memory.write(0x011f, 0x42).unwrap();
+        memory.write(0x0310, 0x43).unwrap();
+        assert_eq!(*latch.lock().unwrap(), vec![(0x0f, 0x42), (0x00, 0x43)]);
+
+        assert!(memory.read(0x0120).is_err());
+        assert!(memory.write(0x010f, 0).is_err());
+        let nothing = || {
+            Box::new(Callbacks {
+                read: |_| 0,
+                write: |_, _| (),
+            })
+        };
+        assert!(memory.attach(0x0118, 0x0120, nothing()).is_err());
+        assert!(memory.attach(0x00f0, 0x0100, nothing()).is_err());
+        assert!(memory.attach(0x0310, 0x0310, nothing()).is_err());
+        assert!(memory.attach(0x0120, 0x0120, nothing()).is_ok());
+    }
+
+    #[test]
+    fn test_banks() {
+        let map = MemoryMap::new()
+            .region(0x0000, 0x00ff, RegionKind::Ram)
+            .region(0x0100, 0x02ff, RegionKind::Banked(3))
+            .region(0x0400, 0x07ff, RegionKind::Mirror(0x03ff));
+        let mut memory = Memory::with_map(&map).unwrap();
+        for bank in 0..3 {
+            memory.select_bank(0x0200, bank).unwrap();
+            memory.write(0x0100, bank + 1).unwrap();
+            memory.write(0x02ff, bank + 0x11).unwrap();
+        }
+        memory.write(0x0000, 0x55).unwrap();
+        assert_eq!(memory.banks(), vec![(0x0100, 2)]);
+
+        for bank in 0..3 {
+            memory.select_bank(0x0100, bank).unwrap();
+            assert_eq!(memory.read(0x0100).unwrap(), bank + 1);
+            assert_eq!(memory.read(0x06ff).unwrap(), bank + 0x11);
+            assert_eq!(memory.read(0x0000).unwrap(), 0x55);
+        }
+        assert!(memory.select_bank(0x0100, 3).is_err());
+        assert!(memory.select_bank(0x0000, 0).is_err());
+
+        memory.watch_code(0x0500);
+        memory.select_bank(0x0100, 0).unwrap();
+        assert!(memory.code_written());
+    }
+
+    #[test]
+    fn test_watch_writes() {
+        let map = MemoryMap::new()
+            .region(0x0000, 0x03ff, RegionKind::Ram)
+            .region(0x0400, 0x07ff, RegionKind::Mirror(0x03ff));
+        let mut memory = Memory::with_map(&map).unwrap();
+        let id = memory.watch_writes(0x0410, 0x041f).unwrap();
+        for address in 0x0010..0x001f {
+            memory.write(address, 1).unwrap();
+        }
+        assert!(!memory.all_written(id));
+        memory.write(0x001f, 1).unwrap();
+        assert!(memory.all_written(id));
+
+        assert!(memory.watch_writes(0x0020, 0x0010).is_err());
+        assert!(memory.watch_writes(0x0300, 0x04ff).is_err());
     }
 }
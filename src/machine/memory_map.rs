@@ -0,0 +1,141 @@
+//! Layout of the 64 KiB address space. Each `MachineInterface` describes its hardware as a list
+//! of page aligned regions; anything not covered is unmapped.
+
+use crate::machine::memory::{Error, PAGE_SIZE};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionKind {
+    Rom,
+    Ram,
+    /// RAM that the display reads the framebuffer from.
+    Vram,
+    /// Undecoded address lines: the region behaves like `address & mask`, which must keep the
+    /// low 8 bits.
+    Mirror(u16),
+    Unmapped,
+    /// Memory-mapped devices.
+    Io,
+    /// RAM with this many banks, switched with `Memory::select_bank`.
+    Banked(u8),
+}
+
+/// `start..=end` of the address space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub(crate) fn len(&self) -> usize {
+        usize::from(self.end) - usize::from(self.start) + 1
+    }
+
+    pub fn contains(&self, address: u16) -> bool {
+        self.start <= address && address <= self.end
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap::default()
+    }
+
+    /// RAM over the whole address space.
+    pub fn flat() -> Self {
+        MemoryMap::new().region(0x0000, 0xffff, RegionKind::Ram)
+    }
+
+    pub fn region(mut self, start: u16, end: u16, kind: RegionKind) -> Self {
+        self.regions.push(Region { start, end, kind });
+        self
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// The region `address` falls in, if any.
+    pub fn find(&self, address: u16) -> Option<&Region> {
+        self.regions.iter().find(|r| r.contains(address))
+    }
+
+    /// Checks that regions are page aligned, don't overlap, and that mirrors keep the low
+    /// address bits.
+    pub fn validate(&self) -> Result<(), Error> {
+        let bad = |r: &Region, why: &str| Err(Error::BadMap(format!("{:?}: {}", r, why)));
+        for (i, r) in self.regions.iter().enumerate() {
+            if r.start > r.end {
+                return bad(r, "ends before it starts");
+            }
+            if usize::from(r.start) % PAGE_SIZE != 0 || r.len() % PAGE_SIZE != 0 {
+                return bad(r, "not page aligned");
+            }
+            match r.kind {
+                RegionKind::Mirror(mask) if mask & 0xff != 0xff => {
+                    return bad(r, "mirror mask drops low address bits");
+                }
+                RegionKind::Banked(0) => return bad(r, "has no banks"),
+                _ => (),
+            }
+            if self.regions[..i]
+                .iter()
+                .any(|o| o.start <= r.end && r.start <= o.end)
+            {
+                return bad(r, "overlaps another region");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::memory::Memory;
+
+    #[test]
+    fn test_validate() {
+        assert!(MemoryMap::flat().validate().is_ok());
+        let overlapping = MemoryMap::new()
+            .region(0x0000, 0x1fff, RegionKind::Rom)
+            .region(0x1f00, 0x23ff, RegionKind::Ram);
+        assert!(overlapping.validate().is_err());
+        let unaligned = MemoryMap::new().region(0x0000, 0x1ffe, RegionKind::Rom);
+        assert!(unaligned.validate().is_err());
+        let mask = MemoryMap::new().region(0x4000, 0xffff, RegionKind::Mirror(0x3f00));
+        assert!(mask.validate().is_err());
+    }
+
+    #[test]
+    fn test_regions() {
+        let map = MemoryMap::new()
+            .region(0x0000, 0x00ff, RegionKind::Rom)
+            .region(0x0100, 0x01ff, RegionKind::Ram)
+            .region(0x0200, 0x02ff, RegionKind::Io)
+            .region(0x0400, 0x07ff, RegionKind::Mirror(0x01ff));
+        let mut memory = Memory::with_map(&map).unwrap();
+        memory.load(0x0000, &[0x11]);
+
+        memory.write(0x0000, 0x22).unwrap();
+        assert_eq!(memory.read(0x0000).unwrap(), 0x11);
+        memory.write(0x0101, 0x33).unwrap();
+        assert_eq!(memory.read(0x0101).unwrap(), 0x33);
+
+        assert_eq!(memory.read(0x0400).unwrap(), 0x11);
+        assert_eq!(memory.read(0x0701).unwrap(), 0x33);
+        memory.write(0x0501, 0x44).unwrap();
+        assert_eq!(memory.read(0x0101).unwrap(), 0x44);
+
+        assert!(memory.read(0x0300).is_err());
+        assert!(memory.write(0x0300, 0).is_err());
+        assert!(memory.read(0x0200).is_err());
+        assert_eq!(memory.len(), 0x10000);
+    }
+}
@@ -1,29 +1,46 @@
+pub mod assembler;
+pub mod cheat;
+pub mod coverage;
 pub mod cpu;
+pub mod disassembly;
 pub mod display;
 mod error;
 pub mod memory;
+pub mod memory_map;
+pub mod movie;
+pub mod nvram;
 pub mod rom;
+pub mod shifter;
+pub mod speed;
+pub mod symbols;
 
 pub use error::Error;
 
+use crate::machine::cheat::{Cheats, Search, SearchCommand};
+use crate::machine::cpu::block::BlockCache;
 pub use crate::machine::cpu::pause;
 pub use crate::machine::cpu::CPUInterface;
+use crate::machine::cpu::Trace;
 pub use crate::machine::cpu::CPU;
-use crate::machine::memory::Memory;
+use crate::machine::display::{FrameSender, Screen};
+use crate::machine::memory::{Memory, WritePolicy};
+use crate::machine::memory_map::MemoryMap;
+use crate::machine::movie::Movie;
+use crate::machine::nvram::Nvram;
 use crate::machine::rom::Rom;
+use crate::machine::speed::{Speed, SpeedControl};
+use crate::machine::symbols::Symbols;
 use crossbeam_channel as channel;
-use crossbeam_channel::Sender;
+use crossbeam_channel::{Receiver, Sender};
 use ggez::event::Keycode;
 use ggez::event::Mod;
-use std::marker::PhantomData;
-use std::sync::Arc;
-use std::sync::RwLock;
-use std::sync::RwLockWriteGuard;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time;
 use std::time::Duration;
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, PartialEq)]
 pub enum MachineEvent {
     KeyDown {
         code: Keycode,
@@ -35,112 +52,430 @@ pub enum MachineEvent {
         keymod: Mod,
         repeat: bool,
     },
+    Speed(SpeedControl),
+    /// From the RAM search console.
+    Search(SearchCommand),
     Exit(u8),
 }
 
-pub trait MachineInterface: Clone {
-    fn handle_in(&self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error>;
-    fn handle_out(&self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error>;
-    fn handle_interrupt(&self, now: &time::Instant, cpu: &mut CPUInterface) -> Result<(), Error>;
-    fn memory_handle(&self) -> Result<RwLockWriteGuard<Memory>, Error>;
+/// Sends events to a running machine from any thread, e.g. to drive a headless one.
+#[derive(Clone)]
+pub struct Controller(Sender<MachineEvent>);
 
-    fn handle_event(&self, evt: MachineEvent) -> Result<(), Error>;
+impl Controller {
+    pub fn send(&self, evt: MachineEvent) {
+        // The channel is unbounded and sending can't fail; if the machine has stopped, the
+        // event is never read, which is fine.
+        self.0.send(evt);
+    }
+
+    pub fn speed(&self, control: SpeedControl) {
+        self.send(MachineEvent::Speed(control));
+    }
+
+    pub fn exit(&self) {
+        self.send(MachineEvent::Exit(0));
+    }
+}
+
+/// A machine's devices: ports, interrupts and input. The emulation thread owns the interface
+/// along with the cpu and memory, so none of it needs locking.
+pub trait MachineInterface {
+    fn handle_in(&mut self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error>;
+    fn handle_out(&mut self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error>;
+    fn handle_interrupt(
+        &mut self,
+        now: &time::Instant,
+        cpu: &mut CPUInterface,
+    ) -> Result<(), Error>;
+
+    fn handle_event(&mut self, evt: MachineEvent) -> Result<(), Error>;
+
+    /// How the hardware lays out the address space. The ROM image is loaded from address 0 and
+    /// write protected where `Rom::rom_regions` says.
+    fn memory_map() -> MemoryMap
+    where
+        Self: Sized;
+
+    /// How the monitor is mounted and what overlay it has.
+    fn screen() -> Screen
+    where
+        Self: Sized,
+    {
+        Screen::default()
+    }
+
+    /// The cpu clock rate in Hz.
+    fn clock_hz() -> u64
+    where
+        Self: Sized,
+    {
+        2_000_000
+    }
+
+    /// Work RAM to keep between sessions, as `start..=end` ranges, e.g. a high score table.
+    fn nvram() -> &'static [(u16, u16)]
+    where
+        Self: Sized,
+    {
+        &[]
+    }
 
-    fn display_refresh(&self, buf: [u8; display::FB_SIZE]);
-    fn apply(memory: Arc<RwLock<Memory>>, sender: Sender<[u8; display::FB_SIZE]>) -> Self
+    /// The key that drives the input called `name`, for replaying movies.
+    fn input(_name: &str) -> Option<Keycode>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Attaches devices to the `Io` regions of the memory map. Called once after `new`; any
+    /// state shared with the devices has to be behind something like a mutex.
+    fn attach_devices(&mut self, _memory: &mut Memory) -> Result<(), memory::Error> {
+        Ok(())
+    }
+
+    fn new() -> Self
     where
         Self: Sized;
 }
 
+/// How often, in milliseconds of emulated time, the emulation thread hands a copy of VRAM to
+/// the display.
+const FRAME_INTERVAL: u64 = 16;
+
 pub struct Machine<I> {
-    cpu: Arc<RwLock<cpu::CPU>>,
-    memory: Arc<RwLock<memory::Memory>>,
-    interface: PhantomData<*const I>,
+    cpu: cpu::CPU,
+    memory: memory::Memory,
+    interface: I,
+    blocks: Option<BlockCache>,
+    coverage: Option<PathBuf>,
+    movie: Option<Movie>,
+    nvram: Option<Nvram>,
+    cheats: Option<Cheats>,
+    search: Option<Search>,
+    search_console: bool,
+    headless: bool,
+    scale: u32,
+    speed: Speed,
+    clock_hz: u64,
+    events: (Sender<MachineEvent>, Receiver<MachineEvent>),
 }
 
-impl<I: MachineInterface + Send + 'static> Machine<I> where {
-    pub fn load<R: Rom<I>>(path: &'static str) -> Result<Machine<I>, &'static str> {
-        let rom = R::load(path).map_err(|_| "failed to read file")?;
-        let mut buf = vec![0x0; 8 * 0x1000];
-        buf.splice(..rom.len(), rom.into_iter());
+impl<I: MachineInterface + Send + 'static> Machine<I> {
+    pub fn load<R: Rom<I>, P: AsRef<Path>>(path: P) -> Result<Machine<I>, String> {
+        let rom = R::load(path)?;
+        let mut memory = Memory::with_map(&I::memory_map()).map_err(|e| e.to_string())?;
+        memory.load(0, &rom);
+        for (start, end) in R::rom_regions() {
+            memory.protect(start, end).map_err(|e| e.to_string())?;
+        }
+        memory.set_write_policy(R::WRITE_POLICY);
+
+        let mut interface = I::new();
+        interface
+            .attach_devices(&mut memory)
+            .map_err(|e| e.to_string())?;
 
-        let memory = Arc::new(RwLock::new(Memory::new(buf)));
         let mut cpu = cpu::new();
         cpu.debug = R::DEBUG;
-        let cpu = Arc::new(RwLock::new(cpu));
 
         Ok(Machine {
-            memory,
             cpu,
-            interface: PhantomData,
+            memory,
+            interface,
+            blocks: None,
+            coverage: None,
+            movie: None,
+            nvram: None,
+            cheats: None,
+            search: None,
+            search_console: false,
+            // Debug ROMs talk through the terminal and have no screen.
+            headless: R::DEBUG,
+            scale: 1,
+            speed: Speed::default(),
+            clock_hz: I::clock_hz(),
+            events: channel::unbounded(),
         })
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        let (tx, rx) = channel::unbounded();
-        let memory = self.memory.clone();
-        let cpu = self.cpu.clone();
-        let interface = I::apply(memory, tx);
-        let interface2 = interface.clone();
-        let debug = self.cpu.read()?.debug;
-
-        let (evt_tx, evt_rx) = channel::unbounded();
-        let th1: thread::JoinHandle<Result<(), Error>> = thread::spawn(move || {
-            let start = time::Instant::now();
-            let mut last_timer = start;
-
-            let timer = channel::tick(Duration::from_millis(1));
-
-            let mut iters = 0;
-            while let Some(now) = timer.recv() {
-                let mut cpu_interface = CPUInterface {
-                    cpu: &mut *cpu.write()?,
-                    memory: &mut *interface.memory_handle()?,
-                };
-
-                let since_last = now - last_timer;
-                let cycles_behind = 2 * since_last.as_micros();
-
-                let mut cycles = 0;
-                while cycles < cycles_behind {
-                    cycles += u128::from(crate::machine::cpu::emulate(
-                        &mut cpu_interface,
-                        &interface,
-                    )?);
-                }
-                if let Some(evt) = evt_rx.try_recv() {
-                    interface.handle_event(evt)?;
+    /// Tracks every memory access for the rest of the session. When the machine stops, a
+    /// report is written to `path` with a `coverage` extension and a disassembler hint file
+    /// with a `hints` extension.
+    pub fn record_coverage<P: AsRef<Path>>(&mut self, path: P) {
+        self.memory.enable_coverage();
+        self.coverage = Some(path.as_ref().to_path_buf());
+    }
+
+    pub fn load_symbols<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.cpu.symbols = Symbols::load(path).map_err(Error::ForeignError)?;
+        Ok(())
+    }
+
+    /// How many executed instructions a cpu error carries. Defaults to
+    /// `cpu::history::DEFAULT_DEPTH`; 0 turns recording off.
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.cpu.history.set_depth(depth);
+    }
+
+    fn write_coverage(&self) -> Result<(), Error> {
+        if let Some(path) = &self.coverage {
+            if let Some(coverage) = self.memory.coverage() {
+                fs::write(path.with_extension("coverage"), coverage.report())?;
+                fs::write(path.with_extension("hints"), coverage.hint_file())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Overrides the ROM's own `WRITE_POLICY`.
+    pub fn set_rom_write_policy(&mut self, policy: WritePolicy) {
+        self.memory.set_write_policy(policy);
+    }
+
+    /// Runs through a cache of decoded basic blocks instead of decoding every instruction.
+    /// The results are the same as without it.
+    pub fn enable_block_cache(&mut self) {
+        self.blocks = Some(BlockCache::new());
+    }
+
+    /// Runs without a window. Input then only comes from a movie.
+    pub fn set_headless(&mut self, headless: bool) {
+        self.headless = headless;
+    }
+
+    /// Draws every pixel as a `scale` by `scale` square.
+    pub fn set_scale(&mut self, scale: u32) {
+        self.scale = scale.max(1);
+    }
+
+    /// Runs `speed` times as fast as the real hardware.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed.apply(SpeedControl::Multiplier(speed));
+    }
+
+    /// Pauses, resumes or changes the speed before the machine runs. Once it's running, use a
+    /// `Controller`.
+    pub fn control_speed(&mut self, control: SpeedControl) {
+        self.speed.apply(control);
+    }
+
+    /// Overrides the interface's `clock_hz`.
+    pub fn set_clock_hz(&mut self, hz: u64) {
+        self.clock_hz = hz;
+    }
+
+    /// Sends input and speed changes to the machine once it's running.
+    pub fn controller(&self) -> Controller {
+        Controller(self.events.0.clone())
+    }
+
+    /// Writes every executed instruction to `path`.
+    pub fn trace_to<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.cpu.trace = Some(Trace::create(path)?);
+        Ok(())
+    }
+
+    /// Stops before the first instruction, and every one after it, until enter is pressed.
+    pub fn start_in_debugger(&mut self) {
+        self.cpu.pause = true;
+    }
+
+    /// Plays back the input recorded in the movie at `path`. Headless machines stop when the
+    /// movie ends.
+    pub fn replay<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.movie = Some(Movie::load(path, I::input).map_err(Error::ForeignError)?);
+        Ok(())
+    }
+
+    /// Keeps the interface's `nvram` ranges in the file at `path`: restored once the game has
+    /// booted, saved when the machine stops. Does nothing for machines without any.
+    pub fn keep_nvram<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        if !I::nvram().is_empty() {
+            self.nvram = Some(Nvram::load(path, I::nvram(), &mut self.memory)?);
+        }
+        Ok(())
+    }
+
+    /// Forces the cheats in the file at `path` from the first frame on, or for `once` cheats,
+    /// from when the game first writes the address.
+    pub fn load_cheats<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.cheats = Some(Cheats::load(path).map_err(Error::ForeignError)?);
+        Ok(())
+    }
+
+    /// Takes RAM search commands from the terminal while running, see `cheat::console`.
+    pub fn search_from_console(&mut self) {
+        self.search_console = true;
+    }
+
+    /// Runs a search command against RAM and prints what is left.
+    fn search(&mut self, command: SearchCommand) -> Result<(), Error> {
+        match (&mut self.search, command) {
+            (Some(search), SearchCommand::Filter(filter)) => search.filter(&self.memory, filter)?,
+            (search, SearchCommand::Filter(filter)) => {
+                let mut new = Search::new(&self.memory)?;
+                new.filter(&self.memory, filter)?;
+                *search = Some(new);
+            }
+            (search, SearchCommand::New) => *search = Some(Search::new(&self.memory)?),
+        }
+        if let Some(search) = &self.search {
+            println!("{}", search.summary());
+        }
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    /// For searching RAM between steps with `cheat::Search`.
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Runs the cpu for at least `cycles` cycles, then lets the interface raise an interrupt.
+    pub fn step(&mut self, now: &time::Instant, cycles: u128) -> Result<(), Error> {
+        let mut cpu_interface = CPUInterface {
+            cpu: &mut self.cpu,
+            memory: &mut self.memory,
+        };
+        if let Some(blocks) = &mut self.blocks {
+            blocks.run(&mut cpu_interface, &mut self.interface, cycles)?;
+        } else {
+            let mut ran = 0;
+            while ran < cycles {
+                ran += u128::from(cpu::emulate(&mut cpu_interface, &mut self.interface)?);
+            }
+        }
+        self.interface.handle_interrupt(now, &mut cpu_interface)
+    }
+
+    /// Emulates on the current thread until the cpu fails, an `Exit` event arrives or a
+    /// headless replay ends. Emulated time advances in 1 ms steps, as many as `speed` says
+    /// are due, so input and interrupts always land between the same instructions. VRAM
+    /// changes are sent every `FRAME_INTERVAL` steps.
+    fn run_loop(&mut self, frames: &FrameSender) -> Result<(), Error> {
+        let start = time::Instant::now();
+        let mut last_timer = start;
+        let mut now = start;
+        // Microseconds of emulated time due but not yet run.
+        let mut due = 0;
+        let mut steps: u64 = 0;
+        let first_cycle = self.cpu.cycles;
+        let mut frame = 0;
+
+        let timer = channel::tick(Duration::from_millis(1));
+        // Nobody is watching a headless replay, so it runs as fast as it can and stops at the
+        // end.
+        let unattended = self.headless && self.movie.is_some();
+        if unattended {
+            self.speed.apply(SpeedControl::Uncapped(true));
+        }
+        self.begin_frame(frame)?;
+        // Until the saved NVRAM has been written back, if there is any.
+        let mut restoring = self.nvram.is_some();
+
+        loop {
+            let tick = if self.speed.paced() {
+                timer.recv()
+            } else {
+                Some(time::Instant::now())
+            };
+            let tick = match tick {
+                Some(tick) => tick,
+                None => return Ok(()),
+            };
+            while let Some(evt) = self.events.1.try_recv() {
+                match evt {
+                    MachineEvent::Exit(_) => return Ok(()),
+                    MachineEvent::Speed(control) => self.speed.apply(control),
+                    MachineEvent::Search(command) => self.search(command)?,
+                    evt => self.interface.handle_event(evt)?,
                 }
+            }
 
-                interface.handle_interrupt(&now, &mut cpu_interface)?;
-                if iters % 100 == 0 {
-                    let mhz = cpu_interface.cpu.cycles as f64 / start.elapsed().as_micros() as f64;
-                    println!("mhz: {}", mhz);
+            let to_frame = FRAME_INTERVAL - steps % FRAME_INTERVAL;
+            due += self
+                .speed
+                .due(tick - last_timer, Duration::from_millis(to_frame));
+            while due >= 1000 {
+                due -= 1000;
+                now += Duration::from_millis(1);
+                steps += 1;
+                // Counted from the start so neither fractional cycles per step nor the
+                // instructions that run over the end of one add up.
+                let target = u128::from(self.clock_hz) * u128::from(steps) / 1000;
+                let ran = self.cpu.cycles - first_cycle;
+                self.step(&now, target.saturating_sub(ran))?;
+                if restoring {
+                    if let Some(nvram) = &mut self.nvram {
+                        restoring = !nvram.restore(&mut self.memory)?;
+                    }
                 }
 
-                if iters % 1000 == 0 {
-                    //println!("{}", cpu_interface.cpu.history)
+                if steps % FRAME_INTERVAL == 0 {
+                    if let Some(changes) = self.memory.vram_changes() {
+                        frames.send(changes);
+                    }
+                    frame += 1;
+                    if self.begin_frame(frame)? && unattended {
+                        return Ok(());
+                    }
                 }
+            }
 
-                last_timer = now;
-                iters += 1;
+            last_timer = tick;
+        }
+    }
+
+    /// Applies cheats and feeds the movie's input for `frame` to the interface. True once the
+    /// movie is over.
+    fn begin_frame(&mut self, frame: u64) -> Result<bool, Error> {
+        if let Some(cheats) = &mut self.cheats {
+            cheats.apply(&mut self.memory)?;
+        }
+        match &mut self.movie {
+            Some(movie) => {
+                for evt in movie.take(frame) {
+                    self.interface.handle_event(evt)?;
+                }
+                Ok(movie.finished())
             }
-            Ok(())
+            None => Ok(false),
+        }
+    }
+
+    pub fn run(self) -> Result<(), Error> {
+        let (tx, rx) = display::frame_channel();
+        let (headless, scale) = (self.headless, self.scale);
+        let controller = self.controller();
+        if self.search_console {
+            cheat::console(controller.clone());
+        }
+
+        let mut machine = self;
+        let emulation = thread::spawn(move || {
+            let result = machine.run_loop(&tx);
+            (machine, result)
         });
 
-        let th2 = thread::spawn(move || {
-            let timer = channel::tick(Duration::from_millis(16));
-            while let Some(_) = timer.recv() {
-                interface2.display_refresh(interface2.memory_handle()?.vram()?)
-            }
+        let display = if headless {
             Ok(())
-        });
+        } else {
+            let result = display::run(I::screen(), scale, rx, controller.clone());
+            controller.exit();
+            result
+        };
 
-        if !debug {
-            display::run(rx, evt_tx)?;
+        let (machine, result) = emulation.join()?;
+        machine.write_coverage()?;
+        if let Some(nvram) = &machine.nvram {
+            nvram.save(&machine.memory)?;
         }
-
-        th1.join()??;
-        th2.join()?
+        display?;
+        result
     }
 }
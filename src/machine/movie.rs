@@ -0,0 +1,133 @@
+//! Recorded input for replaying a session. A movie is a text file with a line per input
+//! change: the frame it happens on, `press` or `release`, and the name of the input, e.g.
+//!
+//! ```text
+//! # insert a coin and start
+//! 60 press coin
+//! 64 release coin
+//! 90 press p1 start
+//! ```
+//!
+//! Frames are `FRAME_INTERVAL` ms of emulated time, so a replay reaches the same state no
+//! matter how fast the host runs it.
+
+use crate::machine::MachineEvent;
+use ggez::event::{Keycode, Mod};
+use std::fs;
+use std::path::Path;
+
+pub struct Movie {
+    /// (frame, event), in frame order.
+    events: Vec<(u64, MachineEvent)>,
+    next: usize,
+}
+
+impl Movie {
+    /// Loads a movie, looking inputs up by name with `key`.
+    pub fn load<P, F>(path: P, key: F) -> Result<Self, String>
+    where
+        P: AsRef<Path>,
+        F: Fn(&str) -> Option<Keycode>,
+    {
+        let s = fs::read_to_string(path).map_err(|e| format!("failed to read movie: {}", e))?;
+        Self::parse(&s, key)
+    }
+
+    pub fn parse<F: Fn(&str) -> Option<Keycode>>(s: &str, key: F) -> Result<Self, String> {
+        let mut events = vec![];
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad = |why: &str| Err(format!("line {}: {}: {}", n + 1, why, line));
+            let mut fields = line.splitn(3, char::is_whitespace);
+            let frame = match fields.next().map(str::parse) {
+                Some(Ok(frame)) => frame,
+                _ => return bad("expected a frame number"),
+            };
+            let down = match fields.next() {
+                Some("press") => true,
+                Some("release") => false,
+                _ => return bad("expected press or release"),
+            };
+            let code = match fields.next().map(str::trim).and_then(&key) {
+                Some(code) => code,
+                None => return bad("unknown input"),
+            };
+            let (keymod, repeat) = (Mod::empty(), false);
+            let event = if down {
+                MachineEvent::KeyDown {
+                    code,
+                    keymod,
+                    repeat,
+                }
+            } else {
+                MachineEvent::KeyUp {
+                    code,
+                    keymod,
+                    repeat,
+                }
+            };
+            events.push((frame, event));
+        }
+        // Stable, so changes on the same frame keep their file order.
+        events.sort_by_key(|&(frame, _)| frame);
+        Ok(Movie { events, next: 0 })
+    }
+
+    /// The events due by `frame` that haven't been taken yet.
+    pub fn take(&mut self, frame: u64) -> Vec<MachineEvent> {
+        let due = self.events[self.next..]
+            .iter()
+            .take_while(|&&(at, _)| at <= frame)
+            .map(|&(_, event)| event)
+            .collect::<Vec<_>>();
+        self.next += due.len();
+        due
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next == self.events.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> Option<Keycode> {
+        match name {
+            "coin" => Some(Keycode::Return),
+            "p1 fire" => Some(Keycode::Space),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut movie = Movie::parse(
+            "# start
+            10 press p1 fire
+            5 press coin
+            12 release   coin
+            12 release p1 fire",
+            key,
+        )
+        .unwrap();
+        assert!(movie.take(4).is_empty());
+        match movie.take(10).as_slice() {
+            [MachineEvent::KeyDown { code: a, .. }, MachineEvent::KeyDown { code: b, .. }] => {
+                assert_eq!((*a, *b), (Keycode::Return, Keycode::Space))
+            }
+            events => panic!("{} events", events.len()),
+        }
+        assert!(!movie.finished());
+        assert_eq!(movie.take(100).len(), 2);
+        assert!(movie.finished());
+
+        assert!(Movie::parse("1 press start", key).is_err());
+        assert!(Movie::parse("x press coin", key).is_err());
+        assert!(Movie::parse("1 hold coin", key).is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! Work RAM kept between sessions, like the battery-backed RAM later boards had, for games that
+//! keep their high scores in plain RAM. The contents are saved on exit and written back on
+//! the next boot, but only once the game has initialized the area itself; any earlier and
+//! the game's own setup would wipe them.
+
+use crate::machine::memory::Memory;
+use crate::machine::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where a game's saved RAM goes: `<user data dir>/emulator/<name>.nv`.
+pub fn default_path(name: &str) -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("emulator").join(format!("{}.nv", name)))
+}
+
+pub struct Nvram {
+    /// `start..=end` ranges, saved one after the other.
+    ranges: &'static [(u16, u16)],
+    path: PathBuf,
+    /// Saved contents still waiting for the game to initialize the ranges.
+    pending: Option<Vec<u8>>,
+    /// `Memory::watch_writes` ids, one per range, until the contents are restored.
+    watches: Vec<usize>,
+}
+
+impl Nvram {
+    /// Reads what was saved at `path`, if anything, and starts watching `ranges` for the game
+    /// to initialize them.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        ranges: &'static [(u16, u16)],
+        memory: &mut Memory,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let pending = match fs::read(&path) {
+            Ok(saved) => Some(saved),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let watches = match pending {
+            Some(_) => ranges
+                .iter()
+                .map(|&(start, end)| memory.watch_writes(start, end))
+                .collect::<Result<_, _>>()?,
+            None => vec![],
+        };
+        if let Some(saved) = &pending {
+            let len: usize = ranges
+                .iter()
+                .map(|&(start, end)| usize::from(end - start) + 1)
+                .sum();
+            if saved.len() != len {
+                return Err(Error::ForeignError(format!(
+                    "{} holds {} bytes, expected {}",
+                    path.display(),
+                    saved.len(),
+                    len
+                )));
+            }
+        }
+        Ok(Nvram {
+            ranges,
+            path,
+            pending,
+            watches,
+        })
+    }
+
+    /// Writes the saved contents back if the game has initialized every range since boot,
+    /// and stops watching the ranges. True once there is nothing left to restore, after
+    /// which it needn't be called again.
+    pub fn restore(&mut self, memory: &mut Memory) -> Result<bool, Error> {
+        if !self.watches.iter().all(|&id| memory.all_written(id)) {
+            return Ok(false);
+        }
+        for id in self.watches.drain(..) {
+            memory.unwatch_writes(id);
+        }
+        if let Some(saved) = self.pending.take() {
+            let addresses = self.ranges.iter().flat_map(|&(start, end)| start..=end);
+            for (address, byte) in addresses.zip(saved) {
+                memory.write(address, byte)?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Saves the ranges. Skipped if the game never got far enough to have the old contents
+    /// restored, so they aren't lost to a short session.
+    pub fn save(&self, memory: &Memory) -> Result<(), Error> {
+        if self.pending.is_some() {
+            return Ok(());
+        }
+        let mut contents = vec![];
+        for &(start, end) in self.ranges {
+            for address in start..=end {
+                contents.push(memory.read(address)?);
+            }
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::memory_map::MemoryMap;
+
+    const SCORE: &[(u16, u16)] = &[(0x20f4, 0x20f5)];
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = std::env::temp_dir().join(format!("nvram-{}.nv", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut memory = Memory::with_map(&MemoryMap::flat()).unwrap();
+        let nvram = Nvram::load(&path, SCORE, &mut memory).unwrap();
+        memory.write(0x20f4, 0x50).unwrap();
+        memory.write(0x20f5, 0x12).unwrap();
+        nvram.save(&memory).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![0x50, 0x12]);
+
+        let mut memory = Memory::with_map(&MemoryMap::flat()).unwrap();
+        let mut nvram = Nvram::load(&path, SCORE, &mut memory).unwrap();
+        // Not before the game has cleared the score itself.
+        memory.write(0x20f4, 0).unwrap();
+        assert!(!nvram.restore(&mut memory).unwrap());
+        assert_eq!(memory.read(0x20f4).unwrap(), 0);
+        // A session that ends here mustn't overwrite the saved score.
+        nvram.save(&memory).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![0x50, 0x12]);
+
+        memory.write(0x20f5, 0).unwrap();
+        assert!(nvram.restore(&mut memory).unwrap());
+        assert_eq!(memory.read(0x20f4).unwrap(), 0x50);
+        assert_eq!(memory.read(0x20f5).unwrap(), 0x12);
+        assert!(nvram.watches.is_empty() && !memory.all_written(0));
+
+        fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(Nvram::load(&path, SCORE, &mut memory).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
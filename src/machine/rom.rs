@@ -1,23 +1,278 @@
-use crate::machine::cpu::disassembler::disassemble;
-use crate::machine::memory::Memory;
+use crate::machine::coverage::Hint;
+use crate::machine::cpu::syntax::Syntax;
+use crate::machine::disassembly;
+use crate::machine::memory::{Memory, WritePolicy};
+use crate::machine::symbols::Symbols;
 use crate::machine::MachineInterface;
-use std::path::Path;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// One chip of a ROM set, named as in the usual dumps. Checksums are only checked when
+/// they're known.
+#[derive(Clone, Copy, Debug)]
+pub struct RomFile {
+    pub name: &'static str,
+    pub start: u16,
+    pub size: usize,
+    pub crc32: Option<u32>,
+    /// Lowercase hex.
+    pub sha1: Option<&'static str>,
+}
+
+impl RomFile {
+    pub const fn new(name: &'static str, start: u16, size: usize) -> Self {
+        RomFile {
+            name,
+            start,
+            size,
+            crc32: None,
+            sha1: None,
+        }
+    }
+
+    pub const fn checked(
+        name: &'static str,
+        start: u16,
+        size: usize,
+        crc32: u32,
+        sha1: &'static str,
+    ) -> Self {
+        RomFile {
+            name,
+            start,
+            size,
+            crc32: Some(crc32),
+            sha1: Some(sha1),
+        }
+    }
+
+    /// What's wrong with `bytes` as a dump of this chip, if anything.
+    fn verify(&self, bytes: &[u8]) -> Option<String> {
+        if bytes.len() != self.size {
+            return Some(format!(
+                "{} is {} bytes, expected {}",
+                self.name,
+                bytes.len(),
+                self.size
+            ));
+        }
+        if let Some(expected) = self.crc32 {
+            let crc32 = crc::crc32::checksum_ieee(bytes);
+            if crc32 != expected {
+                return Some(format!(
+                    "{} has crc32 {:08x}, expected {:08x}",
+                    self.name, crc32, expected
+                ));
+            }
+        }
+        if let Some(expected) = self.sha1 {
+            let sha1 = sha1::Sha1::from(bytes).digest().to_string();
+            if sha1 != expected {
+                return Some(format!(
+                    "{} has sha1 {}, expected {}",
+                    self.name, sha1, expected
+                ));
+            }
+        }
+        None
+    }
+}
+
+/// Where the chips of a set are read from.
+enum Source {
+    Dir(PathBuf),
+    Zip(zip::ZipArchive<File>),
+    /// A file with the chips already spliced together at their addresses.
+    Image(Vec<u8>),
+}
+
+impl Source {
+    fn open(path: &Path) -> Result<Source, String> {
+        let fail = |e: &dyn fmt::Display| format!("failed to read {}: {}", path.display(), e);
+        if path.is_dir() {
+            return Ok(Source::Dir(path.to_path_buf()));
+        }
+        let mut file = File::open(path).map_err(|e| fail(&e))?;
+        if path.extension().map_or(false, |ext| ext == "zip") {
+            return zip::ZipArchive::new(file)
+                .map(Source::Zip)
+                .map_err(|e| fail(&e));
+        }
+        let mut image = vec![];
+        file.read_to_end(&mut image).map_err(|e| fail(&e))?;
+        Ok(Source::Image(image))
+    }
+
+    /// The chip's bytes, or `None` if the source doesn't have it.
+    fn read(&mut self, rom: &RomFile) -> Result<Option<Vec<u8>>, String> {
+        let fail = |e: &dyn fmt::Display| format!("failed to read {}: {}", rom.name, e);
+        match self {
+            Source::Dir(dir) => match fs::read(dir.join(rom.name)) {
+                Ok(bytes) => Ok(Some(bytes)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(fail(&e)),
+            },
+            Source::Zip(archive) => match archive.by_name(rom.name) {
+                Ok(mut file) => {
+                    let mut bytes = vec![];
+                    file.read_to_end(&mut bytes).map_err(|e| fail(&e))?;
+                    Ok(Some(bytes))
+                }
+                Err(zip::result::ZipError::FileNotFound) => Ok(None),
+                Err(e) => Err(fail(&e)),
+            },
+            Source::Image(image) => {
+                let start = usize::from(rom.start);
+                Ok(image
+                    .get(start..start + rom.size)
+                    .map(|bytes| bytes.to_vec()))
+            }
+        }
+    }
+}
+
+/// Reads `roms` from a directory of chips, a zip of them, or a file that's already the
+/// whole image, and splices them into one image loaded from address 0. Every missing or bad
+/// chip is reported, not just the first.
+pub fn load_set<P: AsRef<Path>>(path: P, roms: &[RomFile]) -> Result<Vec<u8>, String> {
+    let mut source = Source::open(path.as_ref())?;
+    let len = roms
+        .iter()
+        .map(|rom| usize::from(rom.start) + rom.size)
+        .max()
+        .unwrap_or(0);
+    let mut image = vec![0; len];
+    let mut problems = vec![];
+    for rom in roms {
+        match source.read(rom)? {
+            None => problems.push(format!("{} is missing", rom.name)),
+            Some(bytes) => match rom.verify(&bytes) {
+                Some(problem) => problems.push(problem),
+                None => {
+                    let start = usize::from(rom.start);
+                    image[start..start + rom.size].copy_from_slice(&bytes);
+                }
+            },
+        }
+    }
+    if problems.is_empty() {
+        Ok(image)
+    } else {
+        Err(format!(
+            "bad rom set {}: {}",
+            path.as_ref().display(),
+            problems.join(", ")
+        ))
+    }
+}
 
 pub trait Rom<I: MachineInterface> {
     const DEBUG: bool;
+    /// What happens when the program writes to `rom_regions`.
+    const WRITE_POLICY: WritePolicy = WritePolicy::Ignore;
+
+    /// Page aligned `(start, end)` ranges, inclusive, that the image is burned into and the
+    /// program can't write to.
+    fn rom_regions() -> Vec<(u16, u16)>;
+
     fn load<P: AsRef<Path>>(p: P) -> Result<Vec<u8>, String>;
     fn dissassembble<P: AsRef<Path>>(p: P) -> Result<String, String> {
+        Self::dissassembble_with(p, Symbols::new(), &Syntax::default(), &[])
+    }
+
+    /// Disassembles to assembler source by following control flow from the reset and
+    /// interrupt vectors plus any code `hints`, e.g. from a coverage run. Bytes that are never
+    /// reached, or that are hinted as data, are emitted as `DB` lines.
+    fn dissassembble_with<P: AsRef<Path>>(
+        p: P,
+        mut symbols: Symbols,
+        syntax: &Syntax,
+        hints: &[(u16, u16, Hint)],
+    ) -> Result<String, String> {
         let buf = Self::load(p)?;
         let mem = Memory::new(buf);
+        let (seeds, data) = disassembly::seeds(hints);
+        let map = disassembly::trace(&mem, &seeds, &data);
+        Ok(disassembly::listing(&mem, &map, &mut symbols, syntax))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
 
-        let mut pc = 0;
+    const DATA: &[u8] = b"The quick brown fox jumps over the lazy dog";
 
-        let mut s = String::new();
-        while pc < mem.len() {
-            let (inst, inc) = disassemble(&mem, pc).unwrap();
-            s.push_str(format!("{:#X?} {}\n", pc, inst).as_str());
-            pc += inc;
+    fn set() -> [RomFile; 2] {
+        [
+            RomFile::checked(
+                "a.bin",
+                0x0000,
+                DATA.len(),
+                0x414f_a339,
+                "2fd4e1c67a2d28fced849ee1bb76e7391b93eb12",
+            ),
+            RomFile::new("b.bin", 0x0100, 4),
+        ]
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A zip archive of `files`, stored uncompressed.
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        for (name, data) in files {
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
         }
-        Ok(s)
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = scratch("romset");
+        fs::write(dir.join("a.bin"), DATA).unwrap();
+        fs::write(dir.join("b.bin"), [1, 2, 3, 4]).unwrap();
+        let image = load_set(&dir, &set()).unwrap();
+        assert_eq!(image.len(), 0x104);
+        assert_eq!(&image[..DATA.len()], DATA);
+        assert_eq!(&image[0x100..], &[1, 2, 3, 4]);
+
+        let mut bad = DATA.to_vec();
+        bad[0] = b't';
+        fs::write(dir.join("a.bin"), bad).unwrap();
+        fs::remove_file(dir.join("b.bin")).unwrap();
+        let err = load_set(&dir, &set()).unwrap_err();
+        assert!(err.contains("a.bin has crc32"), "{}", err);
+        assert!(err.contains("b.bin is missing"), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_zip_and_image() {
+        let dir = scratch("romzip");
+        let archive = dir.join("set.zip");
+        fs::write(&archive, zip(&[("b.bin", &[1, 2, 3, 4]), ("a.bin", DATA)])).unwrap();
+        let image = load_set(&archive, &set()).unwrap();
+        assert_eq!(&image[0x100..], &[1, 2, 3, 4]);
+
+        fs::write(&archive, zip(&[("a.bin", DATA)])).unwrap();
+        assert!(load_set(&archive, &set()).is_err());
+
+        let flat = dir.join("set.rom");
+        fs::write(&flat, &image).unwrap();
+        assert_eq!(load_set(&flat, &set()).unwrap(), image);
+        fs::write(&flat, &image[..0x80]).unwrap();
+        assert!(load_set(&flat, &set()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
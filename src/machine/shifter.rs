@@ -0,0 +1,146 @@
+//! The bit shifter on Midway 8080 boards. The cpu shifts bytes into a 16 bit register from the
+//! top and reads back any 8 bits of it, which saves shifting sprites in software.
+
+/// The ports a board wires the shifter to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShifterPorts {
+    /// Written with the shift amount in the low 3 bits.
+    pub offset: u8,
+    /// Written with the byte to shift in.
+    pub data: u8,
+    /// Read for the shifted result.
+    pub result: u8,
+    /// Read for the shifted result with its bits reversed.
+    pub reversed: Option<u8>,
+    /// Bit 3 of the offset write makes `result` read reversed.
+    pub reverse_bit: bool,
+}
+
+impl ShifterPorts {
+    pub const INVADERS: ShifterPorts = ShifterPorts {
+        offset: 2,
+        data: 4,
+        result: 3,
+        reversed: None,
+        reverse_bit: false,
+    };
+
+    pub const GUN_FIGHT: ShifterPorts = ShifterPorts::INVADERS;
+
+    pub const SEA_WOLF: ShifterPorts = ShifterPorts {
+        offset: 4,
+        data: 3,
+        result: 3,
+        reversed: Some(0),
+        reverse_bit: false,
+    };
+
+    pub const BOOT_HILL: ShifterPorts = ShifterPorts {
+        offset: 1,
+        data: 2,
+        result: 3,
+        reversed: None,
+        reverse_bit: true,
+    };
+}
+
+fn reverse(byte: u8) -> u8 {
+    (0..8).fold(0, |acc, bit| acc | ((byte >> bit) & 1) << (7 - bit))
+}
+
+#[derive(Debug)]
+pub struct Shifter {
+    ports: ShifterPorts,
+    register: u16,
+    offset: u8,
+    reverse: bool,
+}
+
+impl Shifter {
+    pub fn new(ports: ShifterPorts) -> Self {
+        Shifter {
+            ports,
+            register: 0,
+            offset: 0,
+            reverse: false,
+        }
+    }
+
+    /// The 8 bits `offset` bits below the top of the register.
+    pub fn result(&self) -> u8 {
+        ((self.register << self.offset) >> 8) as u8
+    }
+
+    pub fn set_offset(&mut self, value: u8) {
+        self.offset = value & 0x7;
+        self.reverse = self.ports.reverse_bit && value & 0x8 != 0;
+    }
+
+    /// Pushes `value` into the top byte, moving the old top byte down.
+    pub fn shift_in(&mut self, value: u8) {
+        self.register = u16::from(value) << 8 | self.register >> 8;
+    }
+
+    /// The result if `port` is one of the result ports.
+    pub fn read(&self, port: u8) -> Option<u8> {
+        if port == self.ports.result && !self.reverse {
+            Some(self.result())
+        } else if port == self.ports.result || Some(port) == self.ports.reversed {
+            Some(reverse(self.result()))
+        } else {
+            None
+        }
+    }
+
+    /// Handles `port` if it's one of the shifter's, and returns whether it was.
+    pub fn write(&mut self, port: u8, value: u8) -> bool {
+        if port == self.ports.offset {
+            self.set_offset(value);
+        } else if port == self.ports.data {
+            self.shift_in(value);
+        } else {
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shift() {
+        let mut shifter = Shifter::new(ShifterPorts::INVADERS);
+        shifter.shift_in(0xa5);
+        assert_eq!(shifter.result(), 0xa5);
+        shifter.shift_in(0x3c);
+        assert_eq!(shifter.result(), 0x3c);
+        shifter.set_offset(4);
+        assert_eq!(shifter.result(), 0xca);
+        shifter.set_offset(7);
+        assert_eq!(shifter.result(), 0x52);
+        shifter.set_offset(0xf9);
+        assert_eq!(shifter.result(), 0x79);
+    }
+
+    #[test]
+    fn test_ports() {
+        let ports = ShifterPorts {
+            offset: 1,
+            data: 2,
+            result: 3,
+            reversed: Some(4),
+            reverse_bit: true,
+        };
+        let mut shifter = Shifter::new(ports);
+        assert!(shifter.write(2, 0x81));
+        assert!(shifter.write(1, 1));
+        assert!(!shifter.write(4, 0xff));
+        assert_eq!(shifter.read(3), Some(0x02));
+        assert_eq!(shifter.read(4), Some(0x40));
+        assert_eq!(shifter.read(2), None);
+        shifter.write(1, 0x09);
+        assert_eq!(shifter.read(3), Some(0x40));
+    }
+}
@@ -0,0 +1,144 @@
+//! How fast emulated time runs against the host clock: paused, a frame at a time, slowed down,
+//! sped up or as fast as the host can go.
+
+use std::time::Duration;
+
+/// The slowest and fastest multipliers `Slower` and `Faster` go to.
+const MIN_MULTIPLIER: f64 = 0.125;
+const MAX_MULTIPLIER: f64 = 8.0;
+
+/// How many times the multiplier `FastForward` runs at.
+const FAST_FORWARD: f64 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpeedControl {
+    Pause,
+    Resume,
+    TogglePause,
+    /// Runs to the end of the current frame while paused.
+    AdvanceFrame,
+    /// Runs this many times as fast as the hardware.
+    Multiplier(f64),
+    /// Doubles the multiplier.
+    Faster,
+    /// Halves the multiplier.
+    Slower,
+    /// Runs `FAST_FORWARD` times as fast as the multiplier while set.
+    FastForward(bool),
+    /// Runs as fast as the host can while set, whatever the multiplier.
+    Uncapped(bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Speed {
+    pub multiplier: f64,
+    pub paused: bool,
+    pub fast_forward: bool,
+    pub uncapped: bool,
+    /// Frames asked for while paused.
+    advance: u32,
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed {
+            multiplier: 1.0,
+            paused: false,
+            fast_forward: false,
+            uncapped: false,
+            advance: 0,
+        }
+    }
+}
+
+impl Speed {
+    pub fn apply(&mut self, control: SpeedControl) {
+        match control {
+            SpeedControl::Pause => self.paused = true,
+            SpeedControl::Resume => self.paused = false,
+            SpeedControl::TogglePause => self.paused = !self.paused,
+            SpeedControl::AdvanceFrame => {
+                if self.paused {
+                    self.advance += 1;
+                }
+            }
+            SpeedControl::Multiplier(multiplier) => self.multiplier = multiplier,
+            SpeedControl::Faster => {
+                self.multiplier = (self.multiplier * 2.0).min(MAX_MULTIPLIER);
+            }
+            SpeedControl::Slower => {
+                self.multiplier = (self.multiplier / 2.0).max(MIN_MULTIPLIER);
+            }
+            SpeedControl::FastForward(fast_forward) => self.fast_forward = fast_forward,
+            SpeedControl::Uncapped(uncapped) => self.uncapped = uncapped,
+        }
+        if !self.paused {
+            self.advance = 0;
+        }
+    }
+
+    /// Whether the host should wait on its clock between runs.
+    pub fn paced(&self) -> bool {
+        !self.uncapped || self.paused
+    }
+
+    /// Microseconds of emulated time to run after `elapsed` of host time, with the next frame
+    /// ending `to_frame` from now. Uncapped and advancing both run a frame at a time.
+    pub fn due(&mut self, elapsed: Duration, to_frame: Duration) -> u128 {
+        if self.paused {
+            if self.advance == 0 {
+                return 0;
+            }
+            self.advance -= 1;
+            to_frame.as_micros()
+        } else if self.uncapped {
+            to_frame.as_micros()
+        } else if self.fast_forward {
+            (elapsed.as_micros() as f64 * self.multiplier * FAST_FORWARD) as u128
+        } else {
+            (elapsed.as_micros() as f64 * self.multiplier) as u128
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_due() {
+        let ms = Duration::from_millis;
+        let mut speed = Speed::default();
+        assert_eq!(speed.due(ms(3), ms(16)), 3000);
+        speed.apply(SpeedControl::Slower);
+        assert_eq!(speed.due(ms(3), ms(16)), 1500);
+        for _ in 0..10 {
+            speed.apply(SpeedControl::Faster);
+        }
+        assert_eq!(speed.due(ms(1), ms(16)), 8000);
+        speed.apply(SpeedControl::Multiplier(1.0));
+        speed.apply(SpeedControl::FastForward(true));
+        assert_eq!(speed.due(ms(1), ms(16)), 4000);
+        speed.apply(SpeedControl::FastForward(false));
+        assert_eq!(speed.due(ms(1), ms(16)), 1000);
+
+        speed.apply(SpeedControl::Uncapped(true));
+        assert!(!speed.paced());
+        assert_eq!(speed.due(ms(1), ms(5)), 5000);
+
+        speed.apply(SpeedControl::TogglePause);
+        assert!(speed.paced());
+        assert_eq!(speed.due(ms(1), ms(16)), 0);
+        speed.apply(SpeedControl::AdvanceFrame);
+        speed.apply(SpeedControl::AdvanceFrame);
+        assert_eq!(speed.due(ms(1), ms(16)), 16000);
+        assert_eq!(speed.due(ms(1), ms(16)), 16000);
+        assert_eq!(speed.due(ms(1), ms(16)), 0);
+
+        // Frames asked for while paused don't carry over.
+        speed.apply(SpeedControl::AdvanceFrame);
+        speed.apply(SpeedControl::Resume);
+        speed.apply(SpeedControl::Pause);
+        assert_eq!(speed.due(ms(1), ms(16)), 0);
+    }
+}
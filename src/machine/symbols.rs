@@ -0,0 +1,187 @@
+use crate::machine::cpu::callstack::CallStack;
+use crate::machine::cpu::disassembler::disassemble;
+use crate::machine::cpu::ops::Instruction;
+use crate::machine::cpu::syntax::Syntax;
+use crate::machine::memory::Memory;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+/// How far past a symbol an address may be and still be described relative to it.
+const MAX_OFFSET: u16 = 0x100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub comment: Option<String>,
+}
+
+/// Address to label mapping used by the disassembler, the trace log and backtraces.
+///
+/// The file format is one symbol per line, a hex address followed by a name and an optional
+/// `;` comment:
+///
+/// ```text
+/// 01E6 DrawScore ; draws both player scores
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Symbols(BTreeMap<u16, Symbol>);
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols(BTreeMap::new())
+    }
+
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Self, String> {
+        let s = fs::read_to_string(p).map_err(|_| "failed to read symbol file".to_owned())?;
+        Self::parse(&s)
+    }
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::new();
+        for (n, line) in s.lines().enumerate() {
+            let (line, comment) = match line.find(';') {
+                Some(i) => (&line[..i], Some(line[i + 1..].trim().to_owned())),
+                None => (line, None),
+            };
+            let mut parts = line.split_whitespace();
+            let addr = match parts.next() {
+                Some(addr) => addr.trim_start_matches('$').trim_start_matches("0x"),
+                None => continue,
+            };
+            let addr = u16::from_str_radix(addr, 16)
+                .map_err(|_| format!("bad address on line {}: {}", n + 1, addr))?;
+            let name = parts
+                .next()
+                .ok_or_else(|| format!("missing name on line {}", n + 1))?;
+            symbols.insert(addr, name, comment.filter(|c| !c.is_empty()));
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, addr: u16, name: &str, comment: Option<String>) {
+        self.0.insert(
+            addr,
+            Symbol {
+                name: name.to_owned(),
+                comment,
+            },
+        );
+    }
+
+    pub fn get(&self, addr: u16) -> Option<&Symbol> {
+        self.0.get(&addr)
+    }
+
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.0.get(&addr).map(|s| s.name.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u16, &Symbol)> {
+        self.0.iter()
+    }
+
+    /// `DrawScore`, `DrawScore+0x3` or `$01E9` depending on what is known about `addr`.
+    pub fn describe(&self, addr: u16) -> String {
+        match self.0.range(..=addr).next_back() {
+            Some((base, symbol)) if *base == addr => symbol.name.clone(),
+            Some((base, symbol)) if addr - *base < MAX_OFFSET => {
+                format!("{}+{:#X}", symbol.name, addr - *base)
+            }
+            _ => format!("${:04X}", addr),
+        }
+    }
+
+    /// Formats `inst`, replacing its address operand with a label when there is one.
+    pub fn format(&self, inst: &Instruction) -> String {
+        self.format_with(&Syntax::default(), inst)
+    }
+
+    pub fn format_with(&self, syntax: &Syntax, inst: &Instruction) -> String {
+        syntax.format(inst, inst.address().and_then(|a| self.name(a)))
+    }
+
+    /// Adds a `sub_XXXX` or `L_XXXX` label for the target of `inst` if it is a call or jump
+    /// whose target has no label yet.
+    pub fn label(&mut self, inst: &Instruction) {
+        if let Some(target) = inst.address() {
+            if !self.0.contains_key(&target) {
+                if inst.is_call() {
+                    self.insert(target, &format!("sub_{:04X}", target), None);
+                } else if inst.is_jump() {
+                    self.insert(target, &format!("L_{:04X}", target), None);
+                }
+            }
+        }
+    }
+
+    /// Labels every call and jump target found by sweeping `mem` from `start` to `end`.
+    pub fn generate(&mut self, mem: &Memory, start: u16, end: u16) {
+        let mut pc = start;
+        while pc < end {
+            let (inst, inc) = match disassemble(mem, pc) {
+                Ok(r) => r,
+                Err(_) => (Instruction::NOP, 1),
+            };
+            self.label(&inst);
+            pc = pc.saturating_add(inc.max(1));
+        }
+    }
+
+    pub fn backtrace(&self, stack: &CallStack) -> String {
+        let mut s = String::new();
+        writeln!(s, "backtrace:").unwrap();
+        for (i, frame) in stack.frames().iter().rev().enumerate() {
+            writeln!(
+                s,
+                "  #{} {} called from {} ({:?}, sp {:#06X})",
+                i,
+                self.describe(frame.target),
+                self.describe(frame.call_site),
+                frame.kind,
+                frame.sp
+            )
+            .unwrap();
+        }
+        if let (count, Some(last)) = stack.imbalances() {
+            writeln!(s, "  {} unbalanced RET(s), the last: {}", count, last).unwrap();
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        let symbols =
+            Symbols::parse("; space invaders\n01E6 DrawScore ; both players\n$0A12 Loop\n")
+                .unwrap();
+        assert_eq!(
+            symbols.get(0x01E6).unwrap().comment,
+            Some("both players".to_owned())
+        );
+        assert_eq!(
+            symbols.format(&Instruction::CALL(0xE6, 0x01)),
+            "CALL DrawScore"
+        );
+        assert_eq!(symbols.describe(0x0A15), "Loop+0x3");
+        assert_eq!(symbols.describe(0x0009), "$0009");
+    }
+
+    #[test]
+    fn test_generate() {
+        let mem = Memory::new(vec![0xcd, 0x06, 0x00, 0xc3, 0x00, 0x00, 0xc9]);
+        let mut symbols = Symbols::new();
+        symbols.generate(&mem, 0, 7);
+        assert_eq!(symbols.name(0x0006), Some("sub_0006"));
+        assert_eq!(symbols.name(0x0000), Some("L_0000"));
+    }
+}
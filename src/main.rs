@@ -5,6 +5,7 @@
 #![feature(duration_as_u128)]
 #![feature(const_fn)]
 #![feature(const_let)]
+#![feature(test)]
 
 #[macro_use]
 extern crate lazy_static;
@@ -17,25 +18,69 @@ extern crate num;
 #[macro_use]
 extern crate failure;
 
-#[macro_use]
-extern crate ringbuffer;
 extern crate core;
 extern crate crossbeam_channel;
 extern crate ggez;
+#[cfg(test)]
+extern crate test;
 
+#[cfg(test)]
+mod bench;
+mod cli;
 mod diag;
+mod midway;
 mod space_invaders;
 
 use crate::failure::Fail;
 
-mod ring_buffers {
-    impl_ring_buffer!(256, 4);
-}
-
 pub mod machine;
+use crate::cli::Command;
+use crate::machine::coverage;
+use crate::machine::symbols::Symbols;
+use std::env;
+use std::fs;
+use std::process;
+
+/// See `cli::usage()`; with no arguments, plays Space Invaders.
 pub fn main() -> Result<(), machine::Error> {
-    machine::Machine::load::<space_invaders::SpaceInvaders>("roms/invaders.rom")
-        .expect("couldn't load rom")
-        .run()?;
-    Ok(())
+    let command = match cli::parse(env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::usage());
+            process::exit(2);
+        }
+    };
+    match command {
+        Command::Run(game, options) | Command::Replay(game, options) => {
+            midway::run(&game, &options.rom_path(&game), &options)
+        }
+        Command::Disassemble(game, options) => {
+            let symbols = match &options.symbols {
+                Some(path) => Symbols::load(path).map_err(machine::Error::ForeignError)?,
+                None => Symbols::new(),
+            };
+            let hints = match &options.hints {
+                Some(path) => coverage::parse_hints(&fs::read_to_string(path)?)
+                    .map_err(machine::Error::ForeignError)?,
+                None => vec![],
+            };
+            let listing = midway::disassemble(&game, &options.rom_path(&game), symbols, &hints)?;
+            match &options.output {
+                Some(path) => fs::write(path, listing)?,
+                None => print!("{}", listing),
+            }
+            Ok(())
+        }
+        Command::Diag(options) => {
+            let path = options
+                .rom
+                .clone()
+                .unwrap_or_else(|| diag::DEFAULT_PATH.into());
+            diag::run(path, &options)
+        }
+        Command::Help => {
+            print!("{}", cli::usage());
+            Ok(())
+        }
+    }
 }
@@ -0,0 +1,221 @@
+//! The rest of the family. Sound, colour PROMs and analog controls beyond what a key can
+//! press aren't emulated.
+
+use crate::machine::display::{Orientation, Screen};
+use crate::machine::rom::RomFile;
+use crate::machine::shifter::ShifterPorts;
+use crate::midway::{Dip, Driver, Input};
+use crate::space_invaders;
+use ggez::event::Keycode;
+
+const UPRIGHT: Screen = Screen {
+    orientation: Orientation::Upright,
+    overlay: &[],
+};
+
+const ROTATED: Screen = Screen {
+    orientation: Orientation::Rotated,
+    overlay: &[],
+};
+
+const LIVES_3_TO_6: Dip = Dip {
+    name: "lives",
+    port: 2,
+    mask: 0x03,
+    settings: &[("3", 0x00), ("4", 0x01), ("5", 0x02), ("6", 0x03)],
+    default: 0,
+};
+
+const COIN_INFO: Dip = Dip {
+    name: "coin info",
+    port: 2,
+    mask: 0x80,
+    settings: &[("on", 0x00), ("off", 0x80)],
+    default: 0,
+};
+
+/// Gun Fight and Boot Hill: one port per player with a four way stick and a trigger, and
+/// coins, start and the DIP switches on port 2.
+const DUEL_INPUTS: &[Input] = &[
+    Input::low("p1 up", Keycode::W, 0, 0),
+    Input::low("p1 down", Keycode::S, 0, 1),
+    Input::low("p1 left", Keycode::A, 0, 2),
+    Input::low("p1 right", Keycode::D, 0, 3),
+    Input::low("p1 fire", Keycode::LShift, 0, 7),
+    Input::low("p2 up", Keycode::Up, 1, 0),
+    Input::low("p2 down", Keycode::Down, 1, 1),
+    Input::low("p2 left", Keycode::Left, 1, 2),
+    Input::low("p2 right", Keycode::Right, 1, 3),
+    Input::low("p2 fire", Keycode::RShift, 1, 7),
+    Input::high("coin", Keycode::Return, 2, 6),
+    Input::high("start", Keycode::Num1, 2, 7),
+];
+
+const DUEL_DIPS: &[Dip] = &[
+    Dip {
+        name: "coinage",
+        port: 2,
+        mask: 0x03,
+        settings: &[("1 coin 1 play", 0x00), ("1 coin 2 plays", 0x01)],
+        default: 0,
+    },
+    Dip {
+        name: "game time",
+        port: 2,
+        mask: 0x0c,
+        settings: &[("60s", 0x00), ("70s", 0x04), ("80s", 0x08), ("90s", 0x0c)],
+        default: 0,
+    },
+];
+
+pub struct InvadersPartII;
+
+impl Driver for InvadersPartII {
+    const NAME: &'static str = "invadpt2";
+    const DESCRIPTION: &'static str = "Space Invaders Part II (Taito, 1979)";
+    const ROMS: &'static [RomFile] = &[
+        RomFile::new("pv01", 0x0000, 0x800),
+        RomFile::new("pv02", 0x0800, 0x800),
+        RomFile::new("pv03", 0x1000, 0x800),
+        RomFile::new("pv04", 0x1800, 0x800),
+        RomFile::new("pv05", 0x4000, 0x800),
+    ];
+    const PORTS: [u8; 8] = space_invaders::PORTS;
+    const INPUTS: &'static [Input] = space_invaders::INPUTS;
+    const DIPS: &'static [Dip] = &[
+        Dip {
+            name: "lives",
+            port: 2,
+            mask: 0x01,
+            settings: &[("3", 0x00), ("4", 0x01)],
+            default: 0,
+        },
+        COIN_INFO,
+    ];
+    const SHIFTER: ShifterPorts = ShifterPorts::INVADERS;
+    const SCREEN: Screen = ROTATED;
+}
+
+pub struct LunarRescue;
+
+impl Driver for LunarRescue {
+    const NAME: &'static str = "lrescue";
+    const DESCRIPTION: &'static str = "Lunar Rescue (Taito, 1979)";
+    const ROMS: &'static [RomFile] = &[
+        RomFile::new("lrescue.1", 0x0000, 0x800),
+        RomFile::new("lrescue.2", 0x0800, 0x800),
+        RomFile::new("lrescue.3", 0x1000, 0x800),
+        RomFile::new("lrescue.4", 0x1800, 0x800),
+        RomFile::new("lrescue.5", 0x4000, 0x800),
+        RomFile::new("lrescue.6", 0x4800, 0x800),
+    ];
+    const PORTS: [u8; 8] = space_invaders::PORTS;
+    /// Fire is the thruster.
+    const INPUTS: &'static [Input] = space_invaders::INPUTS;
+    const DIPS: &'static [Dip] = &[LIVES_3_TO_6, COIN_INFO];
+    const SHIFTER: ShifterPorts = ShifterPorts::INVADERS;
+    const SCREEN: Screen = ROTATED;
+}
+
+pub struct BalloonBomber;
+
+impl Driver for BalloonBomber {
+    const NAME: &'static str = "ballbomb";
+    const DESCRIPTION: &'static str = "Balloon Bomber (Taito, 1980)";
+    const ROMS: &'static [RomFile] = &[
+        RomFile::new("tn01", 0x0000, 0x800),
+        RomFile::new("tn02", 0x0800, 0x800),
+        RomFile::new("tn03", 0x1000, 0x800),
+        RomFile::new("tn04", 0x1800, 0x800),
+        RomFile::new("tn05-1", 0x4000, 0x800),
+    ];
+    const PORTS: [u8; 8] = space_invaders::PORTS;
+    const INPUTS: &'static [Input] = space_invaders::INPUTS;
+    const DIPS: &'static [Dip] = &[
+        LIVES_3_TO_6,
+        Dip {
+            name: "bonus life",
+            port: 2,
+            mask: 0x08,
+            settings: &[("1000", 0x00), ("1500", 0x08)],
+            default: 0,
+        },
+        COIN_INFO,
+    ];
+    const SHIFTER: ShifterPorts = ShifterPorts::INVADERS;
+    const SCREEN: Screen = ROTATED;
+}
+
+pub struct GunFight;
+
+impl Driver for GunFight {
+    const NAME: &'static str = "gunfight";
+    const DESCRIPTION: &'static str = "Gun Fight (Midway, 1975)";
+    const ROMS: &'static [RomFile] = &[
+        RomFile::new("7609h.bin", 0x0000, 0x400),
+        RomFile::new("7609g.bin", 0x0400, 0x400),
+        RomFile::new("7609f.bin", 0x0800, 0x400),
+        RomFile::new("7609e.bin", 0x0c00, 0x400),
+    ];
+    const PORTS: [u8; 8] = [0xff, 0xff, 0x00, 0, 0, 0, 0, 0];
+    const INPUTS: &'static [Input] = DUEL_INPUTS;
+    const DIPS: &'static [Dip] = DUEL_DIPS;
+    const SHIFTER: ShifterPorts = ShifterPorts::GUN_FIGHT;
+    const SCREEN: Screen = UPRIGHT;
+}
+
+pub struct SeaWolf;
+
+impl Driver for SeaWolf {
+    const NAME: &'static str = "seawolf";
+    const DESCRIPTION: &'static str = "Sea Wolf (Midway, 1976)";
+    const ROMS: &'static [RomFile] = &[
+        RomFile::new("sw0041.h", 0x0000, 0x400),
+        RomFile::new("sw0042.g", 0x0400, 0x400),
+        RomFile::new("sw0043.f", 0x0800, 0x400),
+        RomFile::new("sw0044.e", 0x0c00, 0x400),
+    ];
+    /// The periscope's position is the low 5 bits of port 1. It stays in the middle.
+    const PORTS: [u8; 8] = [0x00, 0x10, 0x00, 0, 0, 0, 0, 0];
+    const INPUTS: &'static [Input] = &[
+        Input::high("fire", Keycode::Space, 1, 5),
+        Input::high("coin", Keycode::Return, 2, 6),
+        Input::high("start", Keycode::Num1, 2, 7),
+    ];
+    const DIPS: &'static [Dip] = &[
+        Dip {
+            name: "game time",
+            port: 2,
+            mask: 0x03,
+            settings: &[("61s", 0x00), ("71s", 0x01), ("81s", 0x02), ("91s", 0x03)],
+            default: 0,
+        },
+        Dip {
+            name: "coinage",
+            port: 2,
+            mask: 0x0c,
+            settings: &[("1 coin 1 play", 0x00), ("1 coin 2 plays", 0x04)],
+            default: 0,
+        },
+    ];
+    const SHIFTER: ShifterPorts = ShifterPorts::SEA_WOLF;
+    const SCREEN: Screen = UPRIGHT;
+}
+
+pub struct BootHill;
+
+impl Driver for BootHill {
+    const NAME: &'static str = "boothill";
+    const DESCRIPTION: &'static str = "Boot Hill (Midway, 1977)";
+    const ROMS: &'static [RomFile] = &[
+        RomFile::new("romh.cpu", 0x0000, 0x800),
+        RomFile::new("romg.cpu", 0x0800, 0x800),
+        RomFile::new("romf.cpu", 0x1000, 0x800),
+        RomFile::new("rome.cpu", 0x1800, 0x800),
+    ];
+    const PORTS: [u8; 8] = [0xff, 0xff, 0x00, 0, 0, 0, 0, 0];
+    const INPUTS: &'static [Input] = DUEL_INPUTS;
+    const DIPS: &'static [Dip] = DUEL_DIPS;
+    const SHIFTER: ShifterPorts = ShifterPorts::BOOT_HILL;
+    const SCREEN: Screen = UPRIGHT;
+}
@@ -0,0 +1,377 @@
+//! Midway's 8080 board and the games that run on it. A `Driver` describes a game as data: its
+//! ROM set, input ports, DIP switches, shifter wiring and monitor. `MidwayInterface` emulates
+//! the board for any of them.
+
+pub mod games;
+
+use crate::cli::Options;
+use crate::machine::coverage::Hint;
+use crate::machine::cpu::syntax::Syntax;
+use crate::machine::display::Screen;
+use crate::machine::memory_map::{MemoryMap, RegionKind};
+use crate::machine::nvram;
+use crate::machine::rom::{self, Rom, RomFile};
+use crate::machine::shifter::{Shifter, ShifterPorts};
+use crate::machine::symbols::Symbols;
+use crate::machine::{CPUInterface, Error, Machine, MachineEvent, MachineInterface};
+use crate::space_invaders::SpaceInvaders;
+use ggez::event::Keycode;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A button or joystick direction, read as one bit of an input port.
+#[derive(Clone, Copy, Debug)]
+pub struct Input {
+    pub name: &'static str,
+    pub key: Keycode,
+    pub port: u8,
+    pub bit: u8,
+    /// The bit reads 0 while pressed.
+    pub active_low: bool,
+}
+
+impl Input {
+    /// An input that sets its bit while pressed.
+    pub const fn high(name: &'static str, key: Keycode, port: u8, bit: u8) -> Self {
+        Input {
+            name,
+            key,
+            port,
+            bit,
+            active_low: false,
+        }
+    }
+
+    /// An input that clears its bit while pressed.
+    pub const fn low(name: &'static str, key: Keycode, port: u8, bit: u8) -> Self {
+        Input {
+            name,
+            key,
+            port,
+            bit,
+            active_low: true,
+        }
+    }
+}
+
+/// DIP switches sharing the `mask` bits of an input port, with the value of each setting.
+#[derive(Clone, Copy, Debug)]
+pub struct Dip {
+    pub name: &'static str,
+    pub port: u8,
+    pub mask: u8,
+    pub settings: &'static [(&'static str, u8)],
+    /// Index into `settings`.
+    pub default: usize,
+}
+
+pub trait Driver: Send + 'static {
+    /// Short name the game is selected by.
+    const NAME: &'static str;
+    const DESCRIPTION: &'static str;
+    const ROMS: &'static [RomFile];
+    /// What input ports 0-7 read with nothing pressed, before the DIP switches.
+    const PORTS: [u8; 8];
+    const INPUTS: &'static [Input];
+    const DIPS: &'static [Dip];
+    const SHIFTER: ShifterPorts;
+    const SCREEN: Screen;
+    /// RAM the high scores are kept in, see `MachineInterface::nvram`.
+    const NVRAM: &'static [(u16, u16)] = &[];
+}
+
+/// Whether any of `D`'s ROMs sit in the second ROM area at 0x4000.
+fn has_upper_rom<D: Driver>() -> bool {
+    D::ROMS.iter().any(|rom| rom.start >= 0x4000)
+}
+
+pub struct MidwayInterface<D> {
+    shifter: Shifter,
+    ports: [u8; 8],
+    next_interrupt: Instant,
+    which_interrupt: u8,
+    driver: PhantomData<D>,
+}
+
+impl<D: Driver> MidwayInterface<D> {
+    fn key(&mut self, code: Keycode, down: bool) {
+        let mut handled = false;
+        for input in D::INPUTS.iter().filter(|input| input.key == code) {
+            let port = &mut self.ports[usize::from(input.port)];
+            if down != input.active_low {
+                *port |= 1 << input.bit;
+            } else {
+                *port &= !(1 << input.bit);
+            }
+            handled = true;
+        }
+        if !handled {
+            println!("unhandled code: {}", code);
+        }
+    }
+}
+
+impl<D: Driver> MachineInterface for MidwayInterface<D> {
+    fn handle_in(&mut self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error> {
+        cpu.cpu.a = match self.shifter.read(port) {
+            Some(value) => value,
+            None => self.ports.get(usize::from(port)).cloned().unwrap_or(0),
+        };
+        Ok(())
+    }
+
+    /// Only the shifter is emulated; sound and the watchdog are ignored.
+    fn handle_out(&mut self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error> {
+        self.shifter.write(port, cpu.cpu.a);
+        Ok(())
+    }
+
+    /// RST 1 halfway down the screen and RST 2 at vblank.
+    fn handle_interrupt(&mut self, now: &Instant, cpu: &mut CPUInterface) -> Result<(), Error> {
+        if cpu.cpu.int_enable == 1 && self.next_interrupt <= *now {
+            if self.which_interrupt == 1 {
+                self.which_interrupt = 2;
+                cpu.interrupt(1)?;
+            } else {
+                self.which_interrupt = 1;
+                cpu.interrupt(2)?;
+            }
+            self.next_interrupt = *now + Duration::from_micros(8000);
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, evt: MachineEvent) -> Result<(), Error> {
+        match evt {
+            MachineEvent::KeyDown { code, .. } => self.key(code, true),
+            MachineEvent::KeyUp { code, .. } => self.key(code, false),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// 8K of ROM, 1K of RAM and the framebuffer, plus another 8K of ROM at 0x4000 on later
+    /// boards. Only 14 or 15 address lines are decoded, so the rest repeats.
+    fn memory_map() -> MemoryMap {
+        // ROM areas are write protected through `rom_regions`.
+        let map = MemoryMap::new()
+            .region(0x0000, 0x1fff, RegionKind::Ram)
+            .region(0x2000, 0x23ff, RegionKind::Ram)
+            .region(0x2400, 0x3fff, RegionKind::Vram);
+        if has_upper_rom::<D>() {
+            map.region(0x4000, 0x5fff, RegionKind::Ram)
+                .region(0x6000, 0x7fff, RegionKind::Mirror(0x3fff))
+                .region(0x8000, 0xffff, RegionKind::Mirror(0x7fff))
+        } else {
+            map.region(0x4000, 0xffff, RegionKind::Mirror(0x3fff))
+        }
+    }
+
+    fn screen() -> Screen {
+        D::SCREEN
+    }
+
+    /// The board's 19.968 MHz crystal divided by 10.
+    fn clock_hz() -> u64 {
+        1_996_800
+    }
+
+    fn nvram() -> &'static [(u16, u16)] {
+        D::NVRAM
+    }
+
+    fn input(name: &str) -> Option<Keycode> {
+        D::INPUTS
+            .iter()
+            .find(|input| input.name == name)
+            .map(|input| input.key)
+    }
+
+    fn new() -> Self {
+        let mut ports = D::PORTS;
+        for dip in D::DIPS {
+            let port = &mut ports[usize::from(dip.port)];
+            *port = *port & !dip.mask | dip.settings[dip.default].1;
+        }
+        MidwayInterface {
+            shifter: Shifter::new(D::SHIFTER),
+            ports,
+            next_interrupt: Instant::now() + Duration::from_micros(16000),
+            which_interrupt: 1,
+            driver: PhantomData,
+        }
+    }
+}
+
+impl<D: Driver> Rom<MidwayInterface<D>> for D {
+    const DEBUG: bool = false;
+
+    fn rom_regions() -> Vec<(u16, u16)> {
+        let mut regions = vec![(0x0000, 0x1fff)];
+        if has_upper_rom::<D>() {
+            regions.push((0x4000, 0x5fff));
+        }
+        regions
+    }
+
+    fn load<P: AsRef<Path>>(p: P) -> Result<Vec<u8>, String> {
+        rom::load_set(p, D::ROMS)
+    }
+}
+
+/// Defines the game list and name lookups over every driver.
+macro_rules! games {
+    ($($game:ty),*) => {
+        const GAMES: &[(&str, &str)] = &[
+            $((<$game as Driver>::NAME, <$game as Driver>::DESCRIPTION)),*
+        ];
+
+        /// Runs the game called `name` with ROMs from `path`.
+        pub fn run(name: &str, path: &Path, options: &Options) -> Result<(), Error> {
+            $(
+                if name == <$game as Driver>::NAME {
+                    return run_game::<$game>(path, options);
+                }
+            )*
+            Err(unknown_game(name))
+        }
+
+        /// Disassembles the game called `name` with ROMs from `path`.
+        pub fn disassemble(
+            name: &str,
+            path: &Path,
+            symbols: Symbols,
+            hints: &[(u16, u16, Hint)],
+        ) -> Result<String, Error> {
+            $(
+                if name == <$game as Driver>::NAME {
+                    return <$game as Rom<MidwayInterface<$game>>>::dissassembble_with(
+                        path,
+                        symbols,
+                        &Syntax::default(),
+                        hints,
+                    )
+                    .map_err(Error::ForeignError);
+                }
+            )*
+            Err(unknown_game(name))
+        }
+    };
+}
+
+games!(
+    SpaceInvaders,
+    games::InvadersPartII,
+    games::LunarRescue,
+    games::BalloonBomber,
+    games::GunFight,
+    games::SeaWolf,
+    games::BootHill
+);
+
+fn unknown_game(name: &str) -> Error {
+    Error::ForeignError(format!(
+        "unknown game {}, expected one of:\n{}",
+        name,
+        list()
+    ))
+}
+
+/// A line per game with its name and description.
+pub fn list() -> String {
+    GAMES
+        .iter()
+        .map(|(name, description)| format!("{:<10} {}\n", name, description))
+        .collect()
+}
+
+/// Where a game's ROMs are looked for by default: `roms/<name>.rom` if it exists, otherwise
+/// the directory `roms/<name>`.
+pub fn default_path(name: &str) -> PathBuf {
+    let file = Path::new("roms").join(format!("{}.rom", name));
+    if file.exists() {
+        file
+    } else {
+        Path::new("roms").join(name)
+    }
+}
+
+fn run_game<D: Driver>(path: &Path, options: &Options) -> Result<(), Error> {
+    let mut machine =
+        Machine::<MidwayInterface<D>>::load::<D, _>(path).map_err(Error::ForeignError)?;
+    options.apply(&mut machine)?;
+    // Replays start from power-on RAM so they play out the same every time.
+    if options.movie.is_none() {
+        if let Some(path) = nvram::default_path(D::NAME) {
+            machine.keep_nvram(path)?;
+        }
+    }
+    machine.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::cpu;
+    use crate::machine::memory::Memory;
+    use crate::midway::games::{BootHill, LunarRescue};
+    use ggez::event::Mod;
+
+    fn key(code: Keycode, down: bool) -> MachineEvent {
+        let (keymod, repeat) = (Mod::empty(), false);
+        if down {
+            MachineEvent::KeyDown {
+                code,
+                keymod,
+                repeat,
+            }
+        } else {
+            MachineEvent::KeyUp {
+                code,
+                keymod,
+                repeat,
+            }
+        }
+    }
+
+    fn read<D: Driver>(interface: &mut MidwayInterface<D>, port: u8) -> u8 {
+        let mut cpu = cpu::new();
+        let mut memory = Memory::new(vec![]);
+        let mut state = CPUInterface {
+            cpu: &mut cpu,
+            memory: &mut memory,
+        };
+        interface.handle_in(&mut state, port).unwrap();
+        cpu.a
+    }
+
+    #[test]
+    fn test_inputs() {
+        let mut invaders = MidwayInterface::<SpaceInvaders>::new();
+        assert_eq!(read(&mut invaders, 1), 0x08);
+        invaders.handle_event(key(Keycode::Space, true)).unwrap();
+        assert_eq!(read(&mut invaders, 1), 0x18);
+        invaders.handle_event(key(Keycode::Space, false)).unwrap();
+        assert_eq!(read(&mut invaders, 1), 0x08);
+
+        let mut boothill = MidwayInterface::<BootHill>::new();
+        assert_eq!(read(&mut boothill, 0), 0xff);
+        boothill.handle_event(key(Keycode::LShift, true)).unwrap();
+        assert_eq!(read(&mut boothill, 0), 0x7f);
+    }
+
+    #[test]
+    fn test_upper_rom() {
+        assert_eq!(
+            LunarRescue::rom_regions(),
+            vec![(0x0000, 0x1fff), (0x4000, 0x5fff)]
+        );
+        let mut memory = Memory::with_map(&MidwayInterface::<LunarRescue>::memory_map()).unwrap();
+        memory.load(0x4800, &[5]);
+        assert_eq!(memory.read(0xc800).unwrap(), 5);
+        memory.write(0x6000, 0x42).unwrap();
+        assert_eq!(memory.read(0xa000).unwrap(), 0x42);
+        assert_eq!(memory.read(0x2000).unwrap(), 0x42);
+    }
+}
@@ -1,149 +1,147 @@
-use crate::machine::display;
-use crate::machine::memory::Memory;
-use crate::machine::CPUInterface;
-use crate::machine::MachineInterface;
-use crossbeam_channel::Sender;
-use std::sync::Arc;
-use std::sync::RwLock;
-use std::sync::RwLockWriteGuard;
-use std::time::Duration;
-use std::time::Instant;
-
-pub struct SpaceInvadersMachineState {
-    shift0: u8,
-    shift1: u8,
-    shift_offset: u8,
-    next_interrupt: Instant,
-    which_interrupt: u8,
-    in_port: u8,
-}
-
-#[derive(Clone)]
-pub struct SpaceInvadersMachineInterface {
-    state: Arc<RwLock<SpaceInvadersMachineState>>,
-
-    memory: Arc<RwLock<Memory>>,
-
-    sender: Sender<[u8; display::FB_SIZE]>,
-}
-
-pub struct SpaceInvaders;
-use crate::machine::rom::Rom;
-use crate::machine::Error;
-use crate::machine::MachineEvent;
+use crate::machine::display::{Band, Orientation, Screen};
+use crate::machine::rom::RomFile;
+use crate::machine::shifter::ShifterPorts;
+use crate::midway::{Dip, Driver, Input};
 use ggez::event::Keycode;
-use std::fs;
-use std::path::Path;
-
-impl MachineInterface for SpaceInvadersMachineInterface {
-    fn handle_in(&self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error> {
-        cpu.cpu.a = match port {
-            0 => 1,
-            1 => self.state.read()?.in_port,
-            3 => {
-                let read = self.state.read()?;
-                let v = u16::from(read.shift1) << 8 | u16::from(read.shift0);
-
-                ((v >> 8u8.wrapping_sub(read.shift_offset)) & 0xff) as u8
-            }
-            _ => 0,
-        };
-
-        Ok(())
-    }
-
-    fn handle_out(&self, cpu: &mut CPUInterface, port: u8) -> Result<(), Error> {
-        let value = cpu.cpu.a;
-        let mut state = self.state.write()?;
-        match port {
-            2 => state.shift_offset = value & 0x7,
-            4 => {
-                state.shift0 = state.shift1;
-                state.shift1 = value;
-            }
-            _ => (),
-        }
-        Ok(())
-    }
-
-    fn handle_interrupt(&self, now: &Instant, cpu: &mut CPUInterface) -> Result<(), Error> {
-        if cpu.cpu.int_enable == 1 && self.state.read()?.next_interrupt <= *now {
-            let mut write = self.state.write()?;
-            if write.which_interrupt == 1 {
-                write.which_interrupt = 2;
-                cpu.interrupt(1)?;
-            } else {
-                write.which_interrupt = 1;
-                cpu.interrupt(2)?;
-            }
-            write.next_interrupt = *now + Duration::from_micros(8000);
-        }
-        Ok(())
-    }
-    fn memory_handle(&self) -> Result<RwLockWriteGuard<Memory>, Error> {
-        Ok(self.memory.write()?)
-    }
 
-    fn handle_event(&self, evt: MachineEvent) -> Result<(), Error> {
-        match evt {
-            MachineEvent::KeyDown { code, .. } => key_down(&mut *self.state.write()?, code),
-            MachineEvent::KeyUp { code, .. } => key_up(&mut *self.state.write()?, code),
-            _ => Ok(()),
-        }
-    }
-
-    fn display_refresh(&self, buf: [u8; display::FB_SIZE]) {
-        self.sender.send(buf)
-    }
-
-    fn apply(memory: Arc<RwLock<Memory>>, sender: Sender<[u8; display::FB_SIZE]>) -> Self {
-        let now = Instant::now();
-        let state = Arc::new(RwLock::new(SpaceInvadersMachineState {
-            shift0: 0,
-            shift1: 0,
-            shift_offset: 0,
-
-            next_interrupt: now + Duration::from_micros(16000),
-            which_interrupt: 1,
-            in_port: 0,
-        }));
-        SpaceInvadersMachineInterface {
-            state,
-
-            memory,
-            sender,
-        }
-    }
-}
-
-fn key_down(state: &mut SpaceInvadersMachineState, code: Keycode) -> Result<(), Error> {
-    match code {
-        Keycode::Return => state.in_port |= 0x1,
-        code => println!("unhandled code: {}", code),
-    }
-    Ok(())
-}
-fn key_up(state: &mut SpaceInvadersMachineState, code: Keycode) -> Result<(), Error> {
-    match code {
-        Keycode::Return => state.in_port &= !0x1,
-        code => println!("unhandled code: {}", code),
-    }
-    Ok(())
-}
-impl Rom<SpaceInvadersMachineInterface> for SpaceInvaders {
-    const DEBUG: bool = false;
+pub struct SpaceInvaders;
 
-    fn load<P: AsRef<Path>>(p: P) -> Result<Vec<u8>, String> {
-        fs::read(p).map_err(|_| "failed to read space invaders rom".to_owned())
-    }
+/// Port 0 is only read by the self test. Bit 3 of port 1 is always set.
+pub const PORTS: [u8; 8] = [0x0e, 0x08, 0x00, 0, 0, 0, 0, 0];
+
+pub const INPUTS: &[Input] = &[
+    Input::high("coin", Keycode::Return, 1, 0),
+    Input::high("p2 start", Keycode::Num2, 1, 1),
+    Input::high("p1 start", Keycode::Num1, 1, 2),
+    Input::high("p1 fire", Keycode::Space, 1, 4),
+    Input::high("p1 left", Keycode::Left, 1, 5),
+    Input::high("p1 right", Keycode::Right, 1, 6),
+    Input::high("tilt", Keycode::T, 2, 2),
+    Input::high("p2 fire", Keycode::W, 2, 4),
+    Input::high("p2 left", Keycode::A, 2, 5),
+    Input::high("p2 right", Keycode::D, 2, 6),
+];
+
+/// The cellophane strips on the monitor: red over the saucer, green over the shields, the
+/// player and the spare bases.
+const OVERLAY: &[Band] = &[
+    Band {
+        x: (0, 224),
+        y: (32, 64),
+        color: [0xff, 0x20, 0x20],
+    },
+    Band {
+        x: (0, 224),
+        y: (184, 240),
+        color: [0x20, 0xff, 0x20],
+    },
+    Band {
+        x: (16, 134),
+        y: (240, 256),
+        color: [0x20, 0xff, 0x20],
+    },
+];
+
+impl Driver for SpaceInvaders {
+    const NAME: &'static str = "invaders";
+    const DESCRIPTION: &'static str = "Space Invaders (Midway, 1978)";
+    const ROMS: &'static [RomFile] = &[
+        RomFile::checked(
+            "invaders.h",
+            0x0000,
+            0x800,
+            0x734f_5ad8,
+            "ff6200af4c9110d8181249cbcef1a8a40fa40b7b",
+        ),
+        RomFile::checked(
+            "invaders.g",
+            0x0800,
+            0x800,
+            0x6bfa_ca4a,
+            "16f48649b531bdef8c2d1446c429b5f414524350",
+        ),
+        RomFile::checked(
+            "invaders.f",
+            0x1000,
+            0x800,
+            0x0cce_ad96,
+            "537aef03468f63c5b9e11dd61e253f7ae17d9743",
+        ),
+        RomFile::checked(
+            "invaders.e",
+            0x1800,
+            0x800,
+            0x14e5_38b0,
+            "1d6ca0c99f9df71e2990b610deb9d7da0125e2d8",
+        ),
+    ];
+    const PORTS: [u8; 8] = PORTS;
+    const INPUTS: &'static [Input] = INPUTS;
+    const DIPS: &'static [Dip] = &[
+        Dip {
+            name: "lives",
+            port: 2,
+            mask: 0x03,
+            settings: &[("3", 0x00), ("4", 0x01), ("5", 0x02), ("6", 0x03)],
+            default: 0,
+        },
+        Dip {
+            name: "bonus life",
+            port: 2,
+            mask: 0x08,
+            settings: &[("1500", 0x00), ("1000", 0x08)],
+            default: 0,
+        },
+        Dip {
+            name: "coin info",
+            port: 2,
+            mask: 0x80,
+            settings: &[("on", 0x00), ("off", 0x80)],
+            default: 0,
+        },
+    ];
+    const SHIFTER: ShifterPorts = ShifterPorts::INVADERS;
+    const SCREEN: Screen = Screen {
+        orientation: Orientation::Rotated,
+        overlay: OVERLAY,
+    };
+    /// The high score, two BCD bytes low first. Boot copies it in from ROM along with the rest
+    /// of the variables, and it's redrawn whenever a game starts.
+    const NVRAM: &'static [(u16, u16)] = &[(0x20f4, 0x20f5)];
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::machine::display;
+    use crate::machine::memory::{Memory, WritePolicy};
+    use crate::machine::rom::Rom;
+    use crate::machine::MachineInterface;
+    use crate::midway::MidwayInterface;
+
     #[test]
-    fn dissassemble_all() {
-        let buf = SpaceInvaders::dissassembble("roms/invaders.rom").unwrap();
-        ::std::fs::write(std::path::Path::new("disassemble.txt"), buf).unwrap();
+    fn test_memory_map() {
+        let mut memory = Memory::with_map(&MidwayInterface::<SpaceInvaders>::memory_map()).unwrap();
+        memory.load(0, &[0xc3]);
+        for (start, end) in SpaceInvaders::rom_regions() {
+            memory.protect(start, end).unwrap();
+        }
+        memory.write(0x0000, 0x00).unwrap();
+        assert_eq!(memory.read(0x4000).unwrap(), 0xc3);
+        memory.write(0x6001, 0x42).unwrap();
+        assert_eq!(memory.read(0x2001).unwrap(), 0x42);
+        assert_eq!(memory.vram_changes().unwrap().rows.len(), display::ROWS);
+        assert!(memory.vram_changes().is_none());
+        memory.write(0x2420, 0xff).unwrap();
+        memory.write(0x6421, 0xfe).unwrap();
+        let frame = memory.vram_changes().unwrap();
+        assert_eq!(frame.rows.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(frame.rows[&1][..2], [0xff, 0xfe]);
+
+        memory.set_write_policy(WritePolicy::Error);
+        assert!(memory.write(0x4000, 0x00).is_err());
+        memory.set_write_policy(WritePolicy::Log);
+        assert!(memory.write(0x0000, 0x00).is_ok());
+        assert_eq!(memory.read(0x0000).unwrap(), 0xc3);
     }
 }